@@ -0,0 +1,226 @@
+use std::error::Error;
+use std::time::Duration;
+use heed3::byteorder::BE;
+use heed3::types::*;
+use heed3::{Database, RoTxn, RwTxn};
+
+pub static KEY_CONFIG_CHUNK_SIZE: &str = "CHUNK_SIZE";
+pub static KEY_CONFIG_CHUNK_AGE: &str = "CHUNK_AGE";
+pub static KEY_CONFIG_CHUNK_MESSAGES: &str = "CHUNK_MESSAGES";
+pub static KEY_CONFIG_CHUNKS_TO_KEEP: &str = "CHUNKS_TO_KEEP";
+pub static KEY_CONFIG_TTL: &str = "TTL";
+pub static KEY_CONFIG_MAX_AGE: &str = "MAX_AGE";
+pub static KEY_CONFIG_MAX_BYTES: &str = "MAX_BYTES";
+pub static KEY_CONFIG_CAPACITY: &str = "CAPACITY";
+pub static KEY_CONFIG_QUOTA_BYTES: &str = "QUOTA_BYTES";
+pub static KEY_CONFIG_QUOTA_MESSAGES: &str = "QUOTA_MESSAGES";
+pub static KEY_CONFIG_QUOTA_LAG: &str = "QUOTA_LAG";
+pub static KEY_CONFIG_QUOTA_POLICY: &str = "QUOTA_POLICY";
+pub static KEY_CONFIG_QUOTA_BLOCK_MS: &str = "QUOTA_BLOCK_MS";
+pub static KEY_CONFIG_DEDUP_COUNT: &str = "DEDUP_COUNT";
+pub static KEY_CONFIG_DEDUP_SECONDS: &str = "DEDUP_SECONDS";
+pub static KEY_CONFIG_PUNCH_HOLES: &str = "PUNCH_HOLES";
+pub static KEY_CONFIG_INDEX_MESSAGES: &str = "INDEX_MESSAGES";
+pub static KEY_CONFIG_INDEX_BYTES: &str = "INDEX_BYTES";
+pub static KEY_CONFIG_ARCHIVE: &str = "ARCHIVE";
+pub static KEY_CONFIG_COMPRESS_ARCHIVE: &str = "COMPRESS_ARCHIVE";
+
+/// How long the hash of a written message is remembered for deduplication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupWindow {
+    /// Remember the last `n` messages of the topic.
+    Count(u64),
+    /// Remember the messages written within the last `n` seconds.
+    Seconds(u64),
+}
+
+/// What a producer does when a push would exceed one of the topic's quotas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotaPolicy {
+    /// Fail the push with `QueueError::QuotaExceeded`.
+    #[default]
+    Reject,
+    /// Wait for consumers or retention to free up space, failing like `Reject` after the timeout.
+    ///
+    /// Pushes within a caller owned transaction cannot wait and are rejected right away.
+    Block(Duration),
+    /// Drop the oldest chunks, consumed or not, to make room for the push.
+    DropOldest,
+}
+
+/// Settings of a topic, stored in its `{name}_config` database when the topic is created.
+///
+/// Producers and consumers read it on every operation, so `Env::alter_topic` applies to open
+/// handles as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicConfig {
+    /// A new chunk file is started once the current one grows past this size.
+    pub chunk_size: u64,
+    /// A new chunk file is started once the first message of the current one is older than this
+    /// many seconds, so retention can free it even on a quiet topic. `Env::enforce_retention`
+    /// rotates as well when nothing is written.
+    pub chunk_age: Option<u64>,
+    /// A new chunk file is started once the current one holds this many messages. Checked before
+    /// each batch, which always goes to a single chunk.
+    pub chunk_messages: Option<u64>,
+    /// Chunks kept on disk before the oldest is dropped, consumed or not.
+    pub chunks_to_keep: u64,
+    /// Seconds after which consumers skip a message, `None` to keep messages forever.
+    pub ttl: Option<u64>,
+    /// Chunks whose newest message is older than this many seconds are dropped.
+    pub max_age: Option<u64>,
+    /// Oldest chunks are dropped while the chunk files of the topic take more than this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Turns the topic into a ring buffer of this many messages: producers skip the oldest
+    /// unconsumed ones past it, so the lag never exceeds it however slow consumers are.
+    pub capacity: Option<u64>,
+    /// Upper bound on the size of the topic's chunk files.
+    pub quota_bytes: Option<u64>,
+    /// Upper bound on the messages retained by the topic, consumed or not.
+    pub quota_messages: Option<u64>,
+    /// Upper bound on the messages not consumed yet.
+    pub quota_lag: Option<u64>,
+    /// Applied when a push would exceed any of the quotas.
+    pub quota_policy: QuotaPolicy,
+    /// Rejects messages whose hash was already written within the window, see `Producer::push_back_with_key`.
+    pub dedup_window: Option<DedupWindow>,
+    /// Once this many bytes of the head chunk are consumed past the last hole, consumers punch a
+    /// hole over the consumed part of the chunk file, so disk usage follows the lag rather than
+    /// `chunk_size`. Scanners and `Env::get` no longer see those messages.
+    pub punch_holes: Option<u64>,
+    /// Messages between two entries of the topic's sparse offset index, which always has one for
+    /// the first message of each chunk. Denser entries make `Env::get` and seeking skip fewer records.
+    pub index_messages: Option<u64>,
+    /// Bytes between two entries of the offset index, whichever of the intervals is reached first.
+    pub index_bytes: Option<u64>,
+    /// Chunks dropped by consumers or retention are moved to the `Env`'s archive directory and
+    /// listed in the topic's manifest there instead of being removed, see `Env::archived`.
+    /// Chunk files are moved as they are unless `compress_archive` is set, and `punch_holes` is
+    /// ignored so archived chunks stay whole.
+    pub archive: bool,
+    /// Archived chunks are gzip compressed. Needs the `compression` cargo feature, topics asking
+    /// for it are rejected otherwise.
+    pub compress_archive: bool,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        TopicConfig {
+            chunk_size: 64 * 1024 * 1024,
+            chunk_age: None,
+            chunk_messages: None,
+            chunks_to_keep: 8,
+            ttl: Some(86400 * 10),
+            max_age: None,
+            max_bytes: None,
+            capacity: None,
+            quota_bytes: None,
+            quota_messages: None,
+            quota_lag: None,
+            quota_policy: QuotaPolicy::Reject,
+            dedup_window: None,
+            punch_holes: None,
+            index_messages: Some(256),
+            index_bytes: Some(64 * 1024),
+            archive: false,
+            compress_archive: false,
+        }
+    }
+}
+
+impl TopicConfig {
+    /// Reads the stored configuration, falling back to defaults for keys never written.
+    ///
+    /// Optional settings are stored as 0 when disabled.
+    pub fn load(txn: &RoTxn, config_db: Database<Str, U64<BE>>) -> Result<Self, Box<dyn Error>> {
+        let default = TopicConfig::default();
+        let dedup_window = match (config_db.get(txn, KEY_CONFIG_DEDUP_COUNT)?, config_db.get(txn, KEY_CONFIG_DEDUP_SECONDS)?) {
+            (Some(n), _) if n > 0 => Some(DedupWindow::Count(n)),
+            (_, Some(secs)) if secs > 0 => Some(DedupWindow::Seconds(secs)),
+            _ => None,
+        };
+
+        let quota_policy = match config_db.get(txn, KEY_CONFIG_QUOTA_POLICY)?.unwrap_or(0) {
+            1 => QuotaPolicy::Block(Duration::from_millis(config_db.get(txn, KEY_CONFIG_QUOTA_BLOCK_MS)?.unwrap_or(0))),
+            2 => QuotaPolicy::DropOldest,
+            _ => QuotaPolicy::Reject,
+        };
+
+        Ok(TopicConfig {
+            chunk_size: config_db.get(txn, KEY_CONFIG_CHUNK_SIZE)?.unwrap_or(default.chunk_size),
+            chunk_age: config_db.get(txn, KEY_CONFIG_CHUNK_AGE)?.filter(|age| *age > 0),
+            chunk_messages: config_db.get(txn, KEY_CONFIG_CHUNK_MESSAGES)?.filter(|n| *n > 0),
+            chunks_to_keep: config_db.get(txn, KEY_CONFIG_CHUNKS_TO_KEEP)?.unwrap_or(default.chunks_to_keep),
+            ttl: match config_db.get(txn, KEY_CONFIG_TTL)? {
+                Some(0) => None,
+                Some(ttl) => Some(ttl),
+                None => default.ttl,
+            },
+            max_age: config_db.get(txn, KEY_CONFIG_MAX_AGE)?.filter(|max_age| *max_age > 0),
+            max_bytes: config_db.get(txn, KEY_CONFIG_MAX_BYTES)?.filter(|max_bytes| *max_bytes > 0),
+            capacity: config_db.get(txn, KEY_CONFIG_CAPACITY)?.filter(|capacity| *capacity > 0),
+            quota_bytes: config_db.get(txn, KEY_CONFIG_QUOTA_BYTES)?.filter(|quota| *quota > 0),
+            quota_messages: config_db.get(txn, KEY_CONFIG_QUOTA_MESSAGES)?.filter(|quota| *quota > 0),
+            quota_lag: config_db.get(txn, KEY_CONFIG_QUOTA_LAG)?.filter(|quota| *quota > 0),
+            quota_policy,
+            dedup_window,
+            punch_holes: config_db.get(txn, KEY_CONFIG_PUNCH_HOLES)?.filter(|bytes| *bytes > 0),
+            index_messages: match config_db.get(txn, KEY_CONFIG_INDEX_MESSAGES)? {
+                Some(0) => None,
+                Some(n) => Some(n),
+                None => default.index_messages,
+            },
+            index_bytes: match config_db.get(txn, KEY_CONFIG_INDEX_BYTES)? {
+                Some(0) => None,
+                Some(n) => Some(n),
+                None => default.index_bytes,
+            },
+            archive: config_db.get(txn, KEY_CONFIG_ARCHIVE)?.unwrap_or(0) > 0,
+            compress_archive: config_db.get(txn, KEY_CONFIG_COMPRESS_ARCHIVE)?.unwrap_or(0) > 0,
+        })
+    }
+
+    pub fn save(&self, txn: &mut RwTxn, config_db: Database<Str, U64<BE>>) -> Result<(), Box<dyn Error>> {
+        config_db.put(txn, KEY_CONFIG_CHUNK_SIZE, &self.chunk_size)?;
+        config_db.put(txn, KEY_CONFIG_CHUNK_AGE, &self.chunk_age.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_CHUNK_MESSAGES, &self.chunk_messages.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_CHUNKS_TO_KEEP, &self.chunks_to_keep)?;
+        config_db.put(txn, KEY_CONFIG_TTL, &self.ttl.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_MAX_AGE, &self.max_age.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_MAX_BYTES, &self.max_bytes.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_CAPACITY, &self.capacity.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_QUOTA_BYTES, &self.quota_bytes.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_QUOTA_MESSAGES, &self.quota_messages.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_QUOTA_LAG, &self.quota_lag.unwrap_or(0))?;
+
+        let (policy, block_ms) = match self.quota_policy {
+            QuotaPolicy::Reject => (0, 0),
+            QuotaPolicy::Block(timeout) => (1, timeout.as_millis() as u64),
+            QuotaPolicy::DropOldest => (2, 0),
+        };
+        config_db.put(txn, KEY_CONFIG_QUOTA_POLICY, &policy)?;
+        config_db.put(txn, KEY_CONFIG_QUOTA_BLOCK_MS, &block_ms)?;
+
+        let (count, seconds) = match self.dedup_window {
+            Some(DedupWindow::Count(n)) => (n, 0),
+            Some(DedupWindow::Seconds(secs)) => (0, secs),
+            None => (0, 0),
+        };
+        config_db.put(txn, KEY_CONFIG_DEDUP_COUNT, &count)?;
+        config_db.put(txn, KEY_CONFIG_DEDUP_SECONDS, &seconds)?;
+        config_db.put(txn, KEY_CONFIG_PUNCH_HOLES, &self.punch_holes.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_INDEX_MESSAGES, &self.index_messages.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_INDEX_BYTES, &self.index_bytes.unwrap_or(0))?;
+        config_db.put(txn, KEY_CONFIG_ARCHIVE, &(self.archive as u64))?;
+        config_db.put(txn, KEY_CONFIG_COMPRESS_ARCHIVE, &(self.compress_archive as u64))?;
+        Ok(())
+    }
+
+    /// Checks that the settings can be applied by this build.
+    pub(crate) fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.compress_archive && !cfg!(feature = "compression") {
+            return Err("compress_archive needs the compression feature".into());
+        }
+        Ok(())
+    }
+}
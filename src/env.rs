@@ -1,31 +1,332 @@
 use std::error::Error;
-use std::path::Path;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use libc::{c_uint, size_t};
 
+use heed3::byteorder::BE;
+use heed3::types::*;
 use heed3::{Database, EnvFlags, EnvOpenOptions, RoTxn, RwTxn, WithTls};
 
-use super::topic::{Consumer, Producer};
+use super::config::TopicConfig;
+use super::error::QueueError;
+use super::options::{EnvOptions, Placement, SyncMode};
+use super::reader::Reader;
+use super::scanner::TopicScanner;
+use super::topic::{self, ArchivedChunk, ChunkInfo, Consumer, IndexEntry, Item, Producer, Receipt, TopicInfo, KEY_CONSUMER_BASE_OFFSET, KEY_CONSUMER_BYTES_READ, KEY_CONSUMER_FILE, KEY_CONSUMER_OFFSET, KEY_CONSUMER_PUNCHED, KEY_CONSUMER_PUNCHED_BYTES, KEY_PRODUCER_BYTES_WRITTEN};
+use super::transaction::Transaction;
+use super::writer;
 
 #[cfg(test)]
 use super::topic::Topic;
 
+/// Upper bound of lmdb databases a single topic may open.
+const DBS_PER_TOPIC: c_uint = 11;
+
+/// How long a map resize waits for the transactions open in other threads to end.
+const RESIZE_WAIT: Duration = Duration::from_secs(1);
+
+/// Suffixes of the lmdb databases a topic may own, `{name}_{suffix}`.
+const TOPIC_DBS: [&str; 11] = ["producer", "consumer", "config", "sequences", "dedup", "dedup_log", "locations", "index", "chunks", "garbage", "archive"];
+
+type ProducerDb = Database<U64<BE>, U64<BE>>;
+type ConsumerDb = Database<Str, U64<BE>>;
+/// Chunk number to the data directory the chunk file was placed in.
+type LocationsDb = Database<U64<BE>, Str>;
+/// Chunk number, path, byte position to read from and records to skip there, see `Env::locate`.
+type Location = (u64, String, u64, u64);
+
 pub struct Env {
-    pub lmdb_env: heed3::Env,
+    /// Only reached through `write_txn` and `read_txn`, a transaction begun without the resize
+    /// gate could have the map resized under it.
+    pub(crate) lmdb_env: heed3::Env,
     pub root: String,
+    /// Directories holding a subdirectory of chunk files per topic, `root` unless the options name
+    /// data directories. The first one also holds chunks placed before locations were recorded.
+    data_dirs: Vec<String>,
+    placement: Placement,
+    /// With the flat layout, the prefix of the chunk file names.
+    chunk_root: String,
+    /// Whether the environment uses the flat layout of `EnvOptions::sub_dir(false)`.
+    flat: bool,
+    read_only: bool,
+    /// Where topics archiving their chunks move them, a subdirectory per topic.
+    archive_dir: String,
+    /// Configuration of topics created implicitly by `Env::producer`.
+    pub(crate) topic_config: TopicConfig,
+    /// Held shared by every transaction and exclusively while the map is resized.
+    resize_gate: RwLock<()>,
+    max_map_size: usize,
+    map_resizes: AtomicU64,
+}
+
+/// An lmdb transaction of an `Env`, which holds off map resizes until it ends.
+///
+/// Derefs to the heed transaction, so it can be passed wherever a `&RoTxn` or `&mut RwTxn` is expected.
+pub struct Txn<'env, T> {
+    txn: T,
+    _resize_guard: RwLockReadGuard<'env, ()>,
+}
+
+impl<T> Deref for Txn<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.txn
+    }
+}
+
+impl<T> DerefMut for Txn<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.txn
+    }
+}
+
+impl<'env> Txn<'env, RwTxn<'env>> {
+    pub fn commit(self) -> heed3::Result<()> {
+        self.txn.commit()
+    }
+}
+
+/// Whether `e` is lmdb running out of map space.
+pub(crate) fn is_map_full(e: &(dyn Error + 'static)) -> bool {
+    matches!(e.downcast_ref::<heed3::Error>(), Some(heed3::Error::Mdb(heed3::MdbError::MapFull)))
+}
+
+/// Moves an environment from the flat layout, an lmdb file `{root}` next to chunk files named
+/// `{root}-{topic}-{n:016x}`, to the directory layout with `{root}/data.mdb` and `{root}/{topic}/{n:016x}`.
+///
+/// Everything is staged in `{root}.migrating`, which is renamed to `{root}` last, so a migration
+/// interrupted by a crash is completed on the next open.
+fn migrate_flat_layout(root: &Path, options: &EnvOptions) -> Result<(), Box<dyn Error>> {
+    let staging = std::path::PathBuf::from(format!("{}.migrating", root.display()));
+    if root.is_file() {
+        let flat = options.clone().sub_dir(false).open(root)?;
+        let topics = flat.topics()?;
+        let old_prefix = std::path::PathBuf::from(&flat.chunk_root);
+        drop(flat);
+
+        let chunk_dir = options.data_dirs.first().cloned().unwrap_or_else(|| staging.clone());
+        let old_dir = old_prefix.parent().ok_or("root has no parent directory")?;
+        let old_name = old_prefix.file_name().ok_or("root has no file name")?.to_string_lossy();
+        std::fs::create_dir_all(&staging)?;
+        for topic in topics {
+            std::fs::create_dir_all(chunk_dir.join(&topic))?;
+            let topic_prefix = format!("{}-{}-", old_name, topic);
+            for entry in std::fs::read_dir(old_dir)? {
+                let file_name = entry?.file_name();
+                // Only the exact prefix followed by a chunk number, topic `a` must not take `a-b`'s chunks.
+                if let Some(file_num) = file_name.to_string_lossy().strip_prefix(&topic_prefix)
+                    && file_num.len() == 16
+                    && file_num.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    std::fs::rename(old_dir.join(&file_name), chunk_dir.join(&topic).join(file_num))?;
+                }
+            }
+        }
+
+        std::fs::rename(root, staging.join("data.mdb"))?;
+        std::fs::remove_file(format!("{}-lock", root.display())).ok();
+    }
+
+    if !root.exists() && staging.join("data.mdb").exists() {
+        std::fs::rename(&staging, root)?;
+    }
+    Ok(())
 }
 
 impl Env {
+    /// Opens an environment with default options, see `EnvOptions` for everything else.
     pub fn new<P: AsRef<Path>>(root: P, max_topics: Option<c_uint>, map_size: Option<size_t>) -> Result<Env, Box<dyn Error>> {
-        let env = unsafe {
-            EnvOpenOptions::new()
-                .map_size(map_size.unwrap_or(256 * 1024 * 1024))
-                .max_dbs(max_topics.unwrap_or(256) * 2)
-                .flags(EnvFlags::NO_SYNC | EnvFlags::NO_SUB_DIR)
-                .open(root.as_ref())
-                .map(|lmdb_env| Env { lmdb_env, root: root.as_ref().to_str().unwrap().to_string() })
+        let mut options = EnvOptions::new();
+        if let Some(max_topics) = max_topics {
+            options = options.max_topics(max_topics);
+        }
+        if let Some(map_size) = map_size {
+            options = options.map_size(map_size);
+        }
+        options.open(root)
+    }
+
+    pub(crate) fn open(root: &Path, options: &EnvOptions) -> Result<Env, Box<dyn Error>> {
+        let mut flags = match options.sync_mode {
+            SyncMode::Sync => EnvFlags::empty(),
+            SyncMode::NoMetaSync => EnvFlags::NO_META_SYNC,
+            SyncMode::NoSync => EnvFlags::NO_SYNC,
         };
+        if !options.sub_dir {
+            flags |= EnvFlags::NO_SUB_DIR;
+        }
+        if options.read_only {
+            flags |= EnvFlags::READ_ONLY;
+        } else {
+            if options.sub_dir {
+                migrate_flat_layout(root, options)?;
+                std::fs::create_dir_all(root)?;
+            }
+            for data_dir in &options.data_dirs {
+                std::fs::create_dir_all(data_dir)?;
+            }
+        }
+
+        let chunk_root = match (options.data_dirs.first(), options.sub_dir) {
+            (Some(data_dir), true) => data_dir.clone(),
+            (Some(data_dir), false) => data_dir.join(root.file_name().ok_or("root has no file name")?),
+            (None, _) => root.to_path_buf(),
+        };
+        let mut data_dirs: Vec<String> = options.data_dirs.iter().map(|dir| dir.to_str().unwrap().to_string()).collect();
+        if data_dirs.is_empty() || !options.sub_dir {
+            data_dirs = vec![chunk_root.to_str().unwrap().to_string()];
+        }
+
+        let mut open_options = EnvOpenOptions::new();
+        open_options
+            .map_size(options.map_size)
+            .max_dbs(options.max_topics * DBS_PER_TOPIC);
+        if let Some(max_readers) = options.max_readers {
+            open_options.max_readers(max_readers);
+        }
+        let lmdb_env = unsafe { open_options.flags(flags).open(root)? };
+
+        let env = Env {
+            lmdb_env,
+            root: root.to_str().unwrap().to_string(),
+            data_dirs,
+            placement: options.placement,
+            chunk_root: chunk_root.to_str().unwrap().to_string(),
+            flat: !options.sub_dir,
+            read_only: options.read_only,
+            archive_dir: match &options.archive_dir {
+                Some(archive_dir) => archive_dir.to_str().unwrap().to_string(),
+                None => format!("{}-archive", root.display()),
+            },
+            topic_config: options.topic_config.clone(),
+            resize_gate: RwLock::new(()),
+            max_map_size: options.max_map_size,
+            map_resizes: AtomicU64::new(0),
+        };
+        if !env.read_only {
+            env.remove_orphans()?;
+        }
+        Ok(env)
+    }
+
+    /// Removes chunk files left behind by processes that died between dropping chunks and removing
+    /// their files: those in each topic's garbage list, and any other below the topic's head.
+    ///
+    /// Files past the tail are left alone, another process may be rotating into them.
+    fn remove_orphans(&self) -> Result<(), Box<dyn Error>> {
+        for name in self.topics()? {
+            let mut txn = self.write_txn()?;
+            let Some((_, consumer_db)) = self.topic_dbs(&txn, &name)? else {
+                continue;
+            };
+            let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+            topic::sweep_garbage(self, &mut txn, &name)?;
+            // Chunks that could not be archived yet stay for the next sweep.
+            let mut pending = vec![];
+            let garbage_db: Option<Database<U64<BE>, DecodeIgnore>> = self.open_db(&txn, &format!("{}_{}", name, "garbage"))?;
+            if let Some(garbage_db) = garbage_db {
+                for entry in garbage_db.iter(&txn)? {
+                    pending.push(entry?.0);
+                }
+            }
+            txn.commit()?;
+
+            for (file_num, path) in self.chunk_files(&name) {
+                if file_num < head && !pending.contains(&file_num) {
+                    std::fs::remove_file(path).ok();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Chunk files of a topic found in the data directories, with their chunk numbers.
+    fn chunk_files(&self, name: &str) -> Vec<(u64, PathBuf)> {
+        let mut files = vec![];
+        for data_dir in &self.data_dirs {
+            let (dir, prefix) = if self.flat {
+                let chunk_root = Path::new(&self.chunk_root);
+                let file_name = chunk_root.file_name().unwrap_or_default().to_string_lossy();
+                (chunk_root.parent().unwrap_or(Path::new(".")).to_path_buf(), format!("{}-{}-", file_name, name))
+            } else {
+                (Path::new(data_dir).join(name), String::new())
+            };
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if let Some(hex) = file_name.strip_prefix(&prefix)
+                    && hex.len() == 16
+                    && let Ok(file_num) = u64::from_str_radix(hex, 16)
+                {
+                    files.push((file_num, entry.path()));
+                }
+            }
+        }
+        files
+    }
+
+    /// Whether the environment was opened with `EnvOptions::read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// How many times the lmdb map has been grown since the `Env` was opened.
+    pub fn map_resizes(&self) -> u64 {
+        self.map_resizes.load(Ordering::Relaxed)
+    }
+
+    /// Doubles the lmdb map up to `max_map_size`, returns whether it grew.
+    ///
+    /// lmdb requires that no transaction is active, so this waits for all of them to end, and
+    /// leaves the map as it is if some are still open after `RESIZE_WAIT`.
+    pub(crate) fn grow_map(&self) -> Result<bool, Box<dyn Error>> {
+        let Some(_gate) = self.lock_resize_gate() else {
+            return Ok(false);
+        };
+        let map_size = self.lmdb_env.info().map_size;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let new_size = map_size.saturating_mul(2).min(self.max_map_size) / page_size * page_size;
+        if new_size <= map_size {
+            return Ok(false);
+        }
+
+        unsafe { self.lmdb_env.resize(new_size)? };
+        self.map_resizes.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Takes the resize gate exclusively once no transaction is open, `None` after `RESIZE_WAIT`.
+    ///
+    /// Never blocks on the lock: a writer waiting on a `RwLock` holds off new readers, so a thread
+    /// nesting transactions would wait for the resize while the resize waits for it.
+    fn lock_resize_gate(&self) -> Option<RwLockWriteGuard<'_, ()>> {
+        let start = Instant::now();
+        loop {
+            if let Ok(gate) = self.resize_gate.try_write() {
+                return Some(gate);
+            }
+            if start.elapsed() >= RESIZE_WAIT {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
 
-        Ok(env?)
+    /// Runs `f` again after growing the map for as long as it fails with a full map.
+    pub(crate) fn retry_map_full<T>(&self, mut f: impl FnMut() -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+        loop {
+            match f() {
+                Err(e) if is_map_full(&*e) && self.grow_map()? => continue,
+                result => return result,
+            }
+        }
     }
 
     pub fn db<K, V>(&self, wtxn: &mut RwTxn, name: &str) -> Result<Database<K, V>, Box<dyn Error>>
@@ -34,83 +335,2008 @@ impl Env {
         Ok(self.lmdb_env.create_database::<K, V>(wtxn, Some(name))?)
     }
 
-    pub fn producer(&self, name: &str, chunk_size: Option<u64>) -> Result<Producer, Box<dyn Error>> {
-        Producer::new(&self, name, chunk_size)
+    /// Opens an existing database, `None` if it was never created.
+    pub fn open_db<K, V>(&self, txn: &RoTxn, name: &str) -> Result<Option<Database<K, V>>, Box<dyn Error>>
+    where K: 'static, V: 'static
+    {
+        Ok(self.lmdb_env.open_database::<K, V>(txn, Some(name))?)
     }
 
-    pub fn consumer(&self, name: &str, chunks_to_keep: Option<u64>) -> Result<Consumer, Box<dyn Error>> {
-        Consumer::new(&self, name, chunks_to_keep)
+    /// Names a topic directory cannot take because a data directory holds files of the environment
+    /// under them: the lmdb files, the root and its migration staging directory, the archive
+    /// directory or another data directory.
+    pub(crate) fn reserved_topic_names(&self) -> Vec<String> {
+        if self.flat {
+            return vec![];
+        }
+        let root = Path::new(&self.root);
+        let mut paths = vec![root.join("data.mdb"), root.join("lock.mdb"), root.to_path_buf()];
+        paths.push(PathBuf::from(format!("{}.migrating", self.root)));
+        paths.push(PathBuf::from(&self.archive_dir));
+        paths.extend(self.data_dirs.iter().map(PathBuf::from));
+
+        // Compared as resolved by the file system where possible, the data directories exist.
+        let resolve = |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let data_dirs: Vec<PathBuf> = self.data_dirs.iter().map(|dir| resolve(Path::new(dir))).collect();
+        paths.iter()
+            .filter(|path| path.parent().is_some_and(|parent| data_dirs.contains(&resolve(parent))))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect()
     }
 
-    pub fn write_txn(&self) -> Result<RwTxn, Box<dyn Error>> {
-        Ok(self.lmdb_env.write_txn()?)
+    fn chunk_path_at(&self, data_dir: &str, name: &str, file_num: u64) -> String {
+        if self.flat {
+            format!("{}-{}-{:016x}", self.chunk_root, name, file_num)
+        } else {
+            format!("{}/{}/{:016x}", data_dir, name, file_num)
+        }
     }
 
-    pub fn read_txn(&self) -> Result<RoTxn<WithTls>, Box<dyn Error>> {
-        Ok(self.lmdb_env.read_txn()?)
+    fn locations_db(&self, txn: &RoTxn, name: &str) -> Result<Option<LocationsDb>, Box<dyn Error>> {
+        self.open_db(txn, &format!("{}_{}", name, "locations"))
     }
-}
 
-#[test]
-fn test_single() -> Result<(), Box<dyn Error>> {
-    let env = Env::new("/tmp/foo_env", None, None)?;
-    let mut producer = env.producer("test", Some(16 *1024 * 1024))?;
-    for i in 0..1024*1024 {
-        producer.push_back(&format!("{}", i).as_bytes())?;
+    /// Path of a chunk file in the data directory recorded when the chunk was placed.
+    ///
+    /// Chunks older than any recorded location were placed before locations were recorded and
+    /// live in the first data directory, any other chunk without a location is unknown.
+    pub fn chunk_path_in(&self, txn: &RoTxn, name: &str, file_num: u64) -> Result<String, Box<dyn Error>> {
+        let located = match self.locations_db(txn, name)? {
+            Some(locations_db) => match locations_db.get(txn, &file_num)? {
+                Some(data_dir) => Some(data_dir.to_string()),
+                None => match locations_db.first(txn)? {
+                    Some((first, _)) if first < file_num => {
+                        return Err(format!("chunk {} of topic {} has no recorded location", file_num, name).into());
+                    },
+                    _ => None,
+                },
+            },
+            None => None,
+        };
+        Ok(self.chunk_path_at(located.as_deref().unwrap_or(&self.data_dirs[0]), name, file_num))
     }
 
-    let mut consumer = env.consumer("test", None)?;
-    let lag = consumer.lag()?;
-    println!("Current lag is: {}", lag);
+    pub fn chunk_path(&self, name: &str, file_num: u64) -> Result<String, Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        self.chunk_path_in(&txn, name, file_num)
+    }
 
-    let mut message_count = 0;
-    loop {
-        let item = consumer.pop_front()?;
-        if let Some(item) = item {
-            message_count += 1;
-            if message_count % (1024 * 100) == 0 {
-                println!("Got message: {}", String::from_utf8(item.data)?);
-                let cur_lag = consumer.lag()?;
-                assert!(lag == cur_lag + message_count as u64);
+    /// Picks the data directory of a new chunk by the placement policy, records it and returns the
+    /// chunk's path.
+    pub(crate) fn place_chunk(&self, txn: &mut RwTxn, name: &str, file_num: u64) -> Result<String, Box<dyn Error>> {
+        let locations_db: LocationsDb = self.db(txn, &format!("{}_{}", name, "locations"))?;
+        let topic_dir = &self.data_dirs[topic::fnv1a(name.as_bytes()) as usize % self.data_dirs.len()];
+        let data_dir = match (self.placement, file_num.checked_sub(1)) {
+            (Placement::PerChunk, _) => self.data_dirs[file_num as usize % self.data_dirs.len()].clone(),
+            // The previous chunk has no location after a purge, or if it predates locations.
+            (Placement::PerTopic, Some(previous)) => locations_db.get(txn, &previous)?.unwrap_or(topic_dir).to_string(),
+            (Placement::PerTopic, None) => topic_dir.clone(),
+        };
+
+        locations_db.put(txn, &file_num, &data_dir)?;
+        if !self.flat {
+            std::fs::create_dir_all(Path::new(&data_dir).join(name))?;
+        }
+        Ok(self.chunk_path_at(&data_dir, name, file_num))
+    }
+
+    /// Forgets where a chunk was placed, once it has left the topic.
+    pub(crate) fn unplace_chunk(&self, txn: &mut RwTxn, name: &str, file_num: u64) -> Result<(), Box<dyn Error>> {
+        if let Some(locations_db) = self.locations_db(txn, name)? {
+            locations_db.delete(txn, &file_num)?;
+        }
+        Ok(())
+    }
+
+    /// Removes chunk files left behind after `file_num` by rotations that never committed.
+    pub(crate) fn remove_chunks_after(&self, name: &str, file_num: u64) {
+        let mut file_num = file_num + 1;
+        while self.data_dirs.iter().filter(|data_dir| std::fs::remove_file(self.chunk_path_at(data_dir, name, file_num)).is_ok()).count() > 0 {
+            file_num += 1;
+        }
+    }
+
+    /// Creates a topic ahead of its producers, failing with `QueueError::TopicExists` if it exists.
+    pub fn create_topic(&self, name: &str, config: &TopicConfig) -> Result<(), Box<dyn Error>> {
+        let mut txn = self.write_txn()?;
+        if !topic::create_topic(self, &mut txn, name, config)? {
+            return Err(QueueError::TopicExists(name.to_string()).into());
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Stored configuration of a topic, failing with `QueueError::TopicNotFound` if it does not exist.
+    pub fn topic_config(&self, name: &str) -> Result<TopicConfig, Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        if self.topic_dbs(&txn, name)?.is_none() {
+            return Err(QueueError::TopicNotFound(name.to_string()).into());
+        }
+        match self.open_db(&txn, &format!("{}_{}", name, "config"))? {
+            Some(config_db) => TopicConfig::load(&txn, config_db),
+            None => Ok(TopicConfig::default()),
+        }
+    }
+
+    /// Replaces the configuration of a topic.
+    ///
+    /// Open producers and consumers pick up the new settings with their next operation.
+    pub fn alter_topic(&self, name: &str, config: &TopicConfig) -> Result<(), Box<dyn Error>> {
+        let mut txn = self.write_txn()?;
+        if self.topic_dbs(&txn, name)?.is_none() {
+            return Err(QueueError::TopicNotFound(name.to_string()).into());
+        }
+        config.validate()?;
+        let config_db = self.db(&mut txn, &format!("{}_{}", name, "config"))?;
+        config.save(&mut txn, config_db)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn producer(&self, name: &str, chunk_size: Option<u64>) -> Result<Producer<'_>, Box<dyn Error>> {
+        Producer::new(self, name, chunk_size)
+    }
+
+    pub fn idempotent_producer(&self, name: &str, producer_id: &str, chunk_size: Option<u64>) -> Result<Producer<'_>, Box<dyn Error>> {
+        Producer::new_idempotent(self, name, producer_id, chunk_size)
+    }
+
+    pub fn consumer(&self, name: &str, chunks_to_keep: Option<u64>) -> Result<Consumer<'_>, Box<dyn Error>> {
+        Consumer::new(self, name, chunks_to_keep)
+    }
+
+    /// Appends each batch to its producer's topic within a single lmdb transaction.
+    ///
+    /// Either every topic advances or none does; on failure the chunk files written so far are
+    /// truncated back. Returns the receipts of each batch, in the order of `batches`.
+    pub fn publish(&self, batches: &mut [(&mut Producer, &[&[u8]])]) -> Result<Vec<Vec<Receipt>>, Box<dyn Error>> {
+        self.retry_map_full(|| self.publish_once(batches))
+    }
+
+    fn publish_once(&self, batches: &mut [(&mut Producer, &[&[u8]])]) -> Result<Vec<Vec<Receipt>>, Box<dyn Error>> {
+        let mut transaction = self.transaction()?;
+        let result = batches.iter_mut()
+            .map(|(producer, messages)| transaction.push_back_batch(producer, messages))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|receipts| {
+                transaction.commit()?;
+                Ok(receipts)
+            });
+
+        if result.is_err() {
+            for (producer, _) in batches.iter_mut() {
+                producer.recover()?;
             }
-        } else {
-            println!("Read {} messages.", message_count);
-            break;
         }
+        result
     }
 
-    Ok(())
-}
+    /// Starts a transaction spanning pops and pushes on any topic of this environment.
+    pub fn transaction(&self) -> Result<Transaction<'_>, Box<dyn Error>> {
+        Transaction::new(self)
+    }
 
-#[test]
-fn test_batch() -> Result<(), Box<dyn Error>> {
-    let env = Env::new("/tmp/foo_env", None, None)?;
-    let mut producer = env.producer("test", Some(16 * 1024 * 1024))?;
-    for i in 0..1024*100 {
-        let vec: Vec<String> = (0..10).map(|v| format!("{}_{}", i, v)).collect();
-        let batch: Vec<&[u8]> = vec.iter().map(|v| v.as_bytes()).collect();
+    /// Names of all topics in this environment.
+    ///
+    /// Deleted topics are left out, though their databases still count towards `EnvOptions::max_topics`.
+    pub fn topics(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        let main_db: Database<Str, DecodeIgnore> = match self.lmdb_env.open_database(&txn, None)? {
+            Some(db) => db,
+            None => return Ok(vec![]),
+        };
 
-        producer.push_back_batch(&batch)?;
+        let mut topics = vec![];
+        for entry in main_db.lazily_decode_data().iter(&txn)? {
+            let (db_name, _) = entry?;
+            // heed stores database names nul terminated.
+            if let Some(name) = db_name.trim_end_matches('\0').strip_suffix("_producer")
+                && self.topic_dbs(&txn, name)?.is_some()
+            {
+                topics.push(name.to_string());
+            }
+        }
+        Ok(topics)
     }
 
-    let mut consumer = env.consumer("test", None)?;
-    let lag = consumer.lag()?;
-    println!("Current lag is: {}", lag);
-    let mut message_count = 0;
-    loop {
-        let items = consumer.pop_front_n(10)?;
-        if items.len() > 0 {
-            message_count += items.len();
-            if message_count % (1024 * 100) == 0 {
-                println!("Got message: {}", String::from_utf8(items[0].data.clone())?);
-                let cur_lag = consumer.lag()?;
-                assert!(lag == cur_lag + message_count as u64);
+    /// Counters and chunk range of a topic, `None` if it does not exist.
+    pub fn topic_info(&self, name: &str) -> Result<Option<TopicInfo>, Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        let (producer_db, consumer_db) = match self.topic_dbs(&txn, name)? {
+            Some(dbs) => dbs,
+            None => return Ok(None),
+        };
+
+        let mut messages = 0;
+        let mut bytes = 0;
+        let mut chunks = 0;
+        for entry in producer_db.iter(&txn)? {
+            let (file_num, count) = entry?;
+            messages += count;
+            chunks += 1;
+            bytes += topic::chunk_info(self, &txn, name, file_num)?.bytes;
+        }
+
+        let head_chunk = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+        let (tail_chunk, _) = producer_db.last(&txn)?.unwrap();
+        let base_offset = consumer_db.get(&txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0);
+        let head_offset = consumer_db.get(&txn, KEY_CONSUMER_OFFSET)?.unwrap_or(0);
+
+        Ok(Some(TopicInfo {
+            name: name.to_string(),
+            head_chunk,
+            tail_chunk,
+            chunks,
+            messages,
+            bytes,
+            head_offset: base_offset + head_offset,
+            tail_offset: base_offset + messages,
+        }))
+    }
+
+    /// Up to `n` messages from the head of a topic, leaving them to the consumer.
+    ///
+    /// Only reads, so it is safe on a read only `Env` next to running producers and consumers.
+    /// Expired messages are included, and a chunk removed by retention in the meantime ends the
+    /// listing early.
+    pub fn peek(&self, name: &str, n: u64) -> Result<Vec<Item>, Box<dyn Error>> {
+        loop {
+            if let Some(items) = self.peek_once(name, n)? {
+                return Ok(items);
             }
-        } else {
-            println!("Read {} messages.", message_count);
-            break;
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    /// Like `peek`, `None` if a consumer punched a hole over messages past the position read
+    /// from the snapshot or moved past its chunk, so the listing has to start over from its new position.
+    fn peek_once(&self, name: &str, n: u64) -> Result<Option<Vec<Item>>, Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        let (producer_db, consumer_db) = match self.topic_dbs(&txn, name)? {
+            Some(dbs) => dbs,
+            None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+
+        let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+        let offset = consumer_db.get(&txn, KEY_CONSUMER_OFFSET)?.unwrap_or(0);
+        let start = consumer_db.get(&txn, KEY_CONSUMER_BYTES_READ)?.unwrap_or(0);
+        let mut next_offset = consumer_db.get(&txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0) + offset;
+        // Only committed messages, bytes past them may belong to an append in progress.
+        let mut chunks = vec![];
+        for entry in producer_db.range(&txn, &(head..))? {
+            let (file_num, count) = entry?;
+            let skipped = if file_num == head { offset } else { 0 };
+            chunks.push((file_num, self.chunk_path_in(&txn, name, file_num)?, count.saturating_sub(skipped)));
+        }
+        drop(txn);
+
+        let mut items = vec![];
+        'chunks: for (file_num, path, count) in chunks {
+            let mut reader = Reader::new(&path, file_num)?;
+            reader.set_bytes_read(if file_num == head { start } else { 0 })?;
+            for _ in 0..count {
+                if items.len() as u64 >= n {
+                    break 'chunks;
+                }
+                match reader.read() {
+                    Ok(mut item) => {
+                        item.offset = next_offset;
+                        items.push(item);
+                        next_offset += 1;
+                    },
+                    Err(_) => break 'chunks,
+                }
+            }
+        }
+
+        // Holes are punched after their commit, one that reached the records read is visible by now.
+        let (committed_head, _, punched_bytes) = self.committed_head(name)?;
+        if committed_head > head || start < punched_bytes {
+            return Ok(None);
+        }
+        Ok(Some(items))
+    }
+
+    /// The message at global `offset` of a topic, `None` once retention dropped it or before it is written.
+    ///
+    /// The chunk is found from the committed message counts and the record within it from the
+    /// topic's sparse offset index, so only a few records are skipped. Consumers are not affected.
+    pub fn get(&self, name: &str, offset: u64) -> Result<Option<Item>, Box<dyn Error>> {
+        loop {
+            if let Some(item) = self.get_once(name, offset)? {
+                return Ok(item);
+            }
+        }
+    }
+
+    /// Like `get`, `None` if a consumer punched a hole over the records read since the snapshot was
+    /// taken, so a new snapshot has to tell whether the message is still retained.
+    fn get_once(&self, name: &str, offset: u64) -> Result<Option<Option<Item>>, Box<dyn Error>> {
+        let Some((file_num, path, position, skip)) = self.locate(name, offset)? else {
+            return Ok(Some(None));
+        };
+
+        let mut reader = Reader::new(&path, file_num)?;
+        let exists = reader.exists()?;
+        let result = match exists {
+            true => reader.set_bytes_read(position).and_then(|_| reader.skip(skip)).and_then(|_| reader.read()),
+            false => Err(anyhow::anyhow!("Chunk file {} does not exist.", path)),
+        };
+
+        // Holes are punched after their commit, one that reached the records read is visible by now.
+        let (head, _, punched_bytes) = self.committed_head(name)?;
+        if file_num < head {
+            return Ok(Some(None));
+        }
+        if file_num == head && position < punched_bytes {
+            return Ok(None);
+        }
+        let mut item = result?;
+        item.offset = offset;
+        Ok(Some(Some(item)))
+    }
+
+    /// Where to read the message at `offset`, from the last indexed record before it, `None` if
+    /// the message is not retained.
+    fn locate(&self, name: &str, offset: u64) -> Result<Option<Location>, Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        let (producer_db, consumer_db) = match self.topic_dbs(&txn, name)? {
+            Some(dbs) => dbs,
+            None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+
+        let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+        let (punched, punched_bytes) = topic::head_start(&txn, consumer_db)?;
+        let mut first_offset = consumer_db.get(&txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0);
+        if offset < first_offset + punched {
+            return Ok(None);
+        }
+        for entry in producer_db.range(&txn, &(head..))? {
+            let (file_num, count) = entry?;
+            if offset >= first_offset + count {
+                first_offset += count;
+                continue;
+            }
+
+            // Chunks written before the index existed are read from their start, or from the end
+            // of the hole punched into the head chunk.
+            let (start, mut position) = if file_num == head { (first_offset + punched, punched_bytes) } else { (first_offset, 0) };
+            let mut skip = offset - start;
+            let index_db: Option<Database<U64<BE>, Bytes>> = self.open_db(&txn, &format!("{}_{}", name, "index"))?;
+            if let Some(index_db) = index_db
+                && let Some((indexed, entry)) = index_db.get_lower_than_or_equal_to(&txn, &offset)?
+                && IndexEntry::decode(entry)?.chunk == file_num
+                && indexed >= start
+            {
+                position = IndexEntry::decode(entry)?.position;
+                skip = offset - indexed;
+            }
+            return Ok(Some((file_num, self.chunk_path_in(&txn, name, file_num)?, position, skip)));
+        }
+        Ok(None)
+    }
+
+    /// Head chunk of a topic as committed now, with the local offset and byte position the hole
+    /// punched into it ends at, see `topic::head_start`.
+    ///
+    /// Holes are only punched once the commit recording them is visible, so records read before
+    /// calling this lie in a hole only if they start before the position returned.
+    pub(crate) fn committed_head(&self, name: &str) -> Result<(u64, u64, u64), Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        let consumer_db = match self.topic_dbs(&txn, name)? {
+            Some((_, consumer_db)) => consumer_db,
+            None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+        let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+        let (punched, punched_bytes) = topic::head_start(&txn, consumer_db)?;
+        Ok((head, punched, punched_bytes))
+    }
+
+    /// Catalog entries of the retained chunks of a topic, oldest first.
+    pub fn chunks(&self, name: &str) -> Result<Vec<ChunkInfo>, Box<dyn Error>> {
+        let txn = self.read_txn()?;
+        let (producer_db, consumer_db) = match self.topic_dbs(&txn, name)? {
+            Some(dbs) => dbs,
+            None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+
+        let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+        let mut chunks = vec![];
+        for entry in producer_db.range(&txn, &(head..))? {
+            chunks.push(topic::chunk_info(self, &txn, name, entry?.0)?);
+        }
+        Ok(chunks)
+    }
+
+    /// Checks the committed bytes of a chunk file against the checksum in the catalog.
+    ///
+    /// Fails for a head chunk with a hole punched into it, see `TopicConfig::punch_holes`.
+    pub fn verify_chunk(&self, name: &str, chunk: u64) -> Result<bool, Box<dyn Error>> {
+        let (info, path) = {
+            let txn = self.read_txn()?;
+            let consumer_db = match self.topic_dbs(&txn, name)? {
+                Some((_, consumer_db)) => consumer_db,
+                None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+            };
+            if consumer_db.get(&txn, KEY_CONSUMER_FILE)? == Some(chunk) && topic::head_start(&txn, consumer_db)?.1 > 0 {
+                return Err(format!("chunk {} of topic {} has been partly reclaimed", chunk, name).into());
+            }
+            (topic::chunk_info(self, &txn, name, chunk)?, self.chunk_path_in(&txn, name, chunk)?)
+        };
+
+        let mut data = std::fs::read(path)?;
+        if (data.len() as u64) < info.bytes {
+            return Ok(false);
+        }
+        data.truncate(info.bytes as usize);
+        Ok(writer::crc32(0, &data) == info.checksum)
+    }
+
+    /// Rebuilds the sparse offset index of a topic from its chunk files, returns the number of entries.
+    ///
+    /// Producers rebuild a missing index when they open, this also applies changed index
+    /// intervals to the chunks already written.
+    pub fn rebuild_index(&self, name: &str) -> Result<u64, Box<dyn Error>> {
+        let mut txn = self.write_txn()?;
+        if self.topic_dbs(&txn, name)?.is_none() {
+            return Err(QueueError::TopicNotFound(name.to_string()).into());
+        }
+        let config = match self.open_db(&txn, &format!("{}_{}", name, "config"))? {
+            Some(config_db) => TopicConfig::load(&txn, config_db)?,
+            None => TopicConfig::default(),
+        };
+
+        let entries = topic::rebuild_index(self, &mut txn, name, &config)?;
+        txn.commit()?;
+        Ok(entries)
+    }
+
+    /// Scans the retained messages of a topic without consuming them, see `TopicScanner`.
+    pub fn scanner(&self, name: &str) -> Result<TopicScanner<'_>, Box<dyn Error>> {
+        TopicScanner::new(self, name)
+    }
+
+    /// Removes a topic with all its messages and chunk files.
+    ///
+    /// Producers and consumers still open on the topic, in this process or another, fail with
+    /// `QueueError::TopicNotFound` from then on. Dropped chunks still waiting to be removed or
+    /// archived are taken care of first. lmdb cannot drop a named
+    /// database through heed, so the topic's databases are emptied and no longer listed, but they
+    /// still count towards `EnvOptions::max_topics`. Creating the topic again reuses them, while
+    /// topics under new names eventually fail with lmdb's `DbsFull` error.
+    pub fn delete_topic(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        let mut txn = self.write_txn()?;
+        let (head, tail) = match self.topic_dbs(&txn, name)? {
+            Some((producer_db, consumer_db)) => (
+                consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0),
+                producer_db.last(&txn)?.unwrap().0,
+            ),
+            None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+        let mut paths = self.chunk_paths(&txn, name, head, tail)?;
+
+        // Once the garbage list is gone nothing would remove its files, those failing to be
+        // archived go with the rest.
+        topic::sweep_garbage(self, &mut txn, name)?;
+        let garbage_db: Option<Database<U64<BE>, Str>> = self.open_db(&txn, &format!("{}_{}", name, "garbage"))?;
+        if let Some(garbage_db) = garbage_db {
+            for entry in garbage_db.iter(&txn)? {
+                paths.push(entry?.1.to_string());
+            }
+        }
+
+        for suffix in TOPIC_DBS {
+            let db: Option<Database<Bytes, DecodeIgnore>> = self.open_db(&txn, &format!("{}_{}", name, suffix))?;
+            if let Some(db) = db {
+                db.clear(&mut txn)?;
+            }
+        }
+        txn.commit()?;
+
+        for path in paths {
+            std::fs::remove_file(path).ok();
+        }
+        if !self.flat {
+            for data_dir in &self.data_dirs {
+                std::fs::remove_dir(Path::new(data_dir).join(name)).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops every message of a topic but keeps the topic, its configuration and its offsets.
+    ///
+    /// Offsets keep growing from where they were, and open handles move on to a fresh chunk.
+    pub fn purge_topic(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        let mut txn = self.write_txn()?;
+        let (producer_db, consumer_db) = match self.topic_dbs(&txn, name)? {
+            Some(dbs) => dbs,
+            None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+
+        let mut messages = 0;
+        for entry in producer_db.iter(&txn)? {
+            messages += entry?.1;
+        }
+        let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+        let (tail, _) = producer_db.last(&txn)?.unwrap();
+        let base_offset = consumer_db.get(&txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0);
+        let paths = self.chunk_paths(&txn, name, head, tail)?;
+        for (file_num, path) in (head..=tail).zip(&paths) {
+            topic::record_garbage(self, &mut txn, name, file_num, path)?;
+        }
+
+        producer_db.clear(&mut txn)?;
+        producer_db.put(&mut txn, &(tail + 1), &0)?;
+        consumer_db.put(&mut txn, KEY_CONSUMER_FILE, &(tail + 1))?;
+        consumer_db.put(&mut txn, KEY_CONSUMER_OFFSET, &0)?;
+        consumer_db.put(&mut txn, KEY_CONSUMER_BYTES_READ, &0)?;
+        consumer_db.put(&mut txn, KEY_CONSUMER_BASE_OFFSET, &(base_offset + messages))?;
+        consumer_db.put(&mut txn, KEY_PRODUCER_BYTES_WRITTEN, &0)?;
+        consumer_db.put(&mut txn, KEY_CONSUMER_PUNCHED, &0)?;
+        consumer_db.put(&mut txn, KEY_CONSUMER_PUNCHED_BYTES, &0)?;
+        for suffix in ["dedup", "dedup_log", "locations", "index", "chunks"] {
+            let db: Option<Database<Bytes, DecodeIgnore>> = self.open_db(&txn, &format!("{}_{}", name, suffix))?;
+            if let Some(db) = db {
+                db.clear(&mut txn)?;
+            }
+        }
+
+        // Consumers reopen the new head right away, it has to exist before anything is written.
+        std::fs::File::create(self.place_chunk(&mut txn, name, tail + 1)?)?;
+        topic::start_chunk(self, &mut txn, name, tail + 1)?;
+        txn.commit()?;
+
+        for path in paths {
+            std::fs::remove_file(path).ok();
+        }
+        Ok(())
+    }
+
+    /// Applies the retention settings of every topic, returns the number of chunks dropped.
+    ///
+    /// Producers and consumers already do this on each operation; calling it periodically, e.g.
+    /// from a janitor thread, also frees the space of topics nobody is writing to or reading from.
+    /// Tail chunks past `TopicConfig::chunk_age` are rotated first, so they can expire in turn, and
+    /// holes marked by consumers are punched. Chunk files whose removal or archiving was cut short by a
+    /// crash or failed are taken care of as well.
+    pub fn enforce_retention(&self) -> Result<u64, Box<dyn Error>> {
+        let mut dropped = 0;
+        for name in self.topics()? {
+            let mut txn = self.write_txn()?;
+            let (producer_db, consumer_db) = match self.topic_dbs(&txn, &name)? {
+                Some(dbs) => dbs,
+                None => continue,
+            };
+            let config = match self.open_db(&txn, &format!("{}_{}", name, "config"))? {
+                Some(config_db) => TopicConfig::load(&txn, config_db)?,
+                None => TopicConfig::default(),
+            };
+
+            // Before anything is dropped here, so the list only holds drops that have committed.
+            topic::sweep_garbage(self, &mut txn, &name)?;
+
+            let (tail, count) = producer_db.last(&txn)?.unwrap();
+            if topic::rotation_due(&config, &topic::chunk_info(self, &txn, &name, tail)?, count) {
+                std::fs::File::create(topic::start_tail(self, &mut txn, &name, producer_db, consumer_db, tail + 1)?)?;
+            }
+
+            let chunks = topic::enforce_retention(self, &mut txn, &name, producer_db, consumer_db, &config)?;
+            // Holes marked by pops in caller owned transactions are only punched here.
+            let hole = match config.punch_holes {
+                Some(_) => {
+                    let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+                    Some((self.chunk_path_in(&txn, &name, head)?, topic::head_start(&txn, consumer_db)?.1))
+                },
+                None => None,
+            };
+            txn.commit()?;
+
+            topic::discard_chunks(self, &name, &chunks);
+            if let Some((path, len)) = hole {
+                topic::punch_hole(&path, len).ok();
+            }
+            dropped += chunks.len() as u64;
+        }
+        Ok(dropped)
+    }
+
+    /// Moves a dropped chunk file to the topic's archive directory and lists it in the manifest.
+    ///
+    /// The file is renamed next to itself first, so of several processes discarding the same
+    /// chunk only one claims it. Every step can be repeated: an attempt cut short by a crash or a
+    /// failure is resumed from the claimed file or from the archived one, and the chunk is listed
+    /// in the manifest before its archive record may be dropped. An archived file is never overwritten.
+    pub(crate) fn archive_chunk(&self, name: &str, archived: ArchivedChunk, path: &str) -> Result<(), Box<dyn Error>> {
+        let dir = Path::new(&self.archive_dir).join(name);
+        let target = dir.join(archived.file_name());
+        let claimed = format!("{}.archiving", path);
+        match std::fs::rename(path, &claimed) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            result => result?,
+        }
+
+        if Path::new(&claimed).exists() {
+            std::fs::create_dir_all(&dir)?;
+            store_archived(Path::new(&claimed), &target, &archived)?;
+            match std::fs::remove_file(&claimed) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                result => result?,
+            }
+        } else if !target.exists() {
+            // Neither claimed nor archived, another process took care of it.
+            return Ok(());
+        }
+        list_archived(&dir, archived)
+    }
+
+    /// Chunks of a topic moved to the archive, in the order they were archived, see
+    /// `TopicConfig::archive`. Works on a read only `Env` and for deleted topics too.
+    ///
+    /// Manifest lines that do not parse, such as one torn by a crash while it was written, are
+    /// skipped. The chunk of a torn line is listed again once it is archived anew.
+    pub fn archived(&self, name: &str) -> Result<Vec<ArchivedChunk>, Box<dyn Error>> {
+        let manifest = match std::fs::read_to_string(Path::new(&self.archive_dir).join(name).join("MANIFEST")) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(manifest.lines().filter_map(|line| ArchivedChunk::from_line(line).ok()).collect())
+    }
+
+    /// Appends the messages of an archived chunk of topic `name` to topic `into` for replay,
+    /// returns how many were appended.
+    ///
+    /// `archived` is an entry of `archived(name)`, which tells chunks with the same number from
+    /// earlier lifetimes of the topic apart by their generation. The chunk file is checked against
+    /// the entry's checksum first. Messages get new offsets and timestamps in `into`, which may be
+    /// `name` itself, and the archive is left as it is.
+    pub fn import_archived(&self, name: &str, archived: &ArchivedChunk, into: &str) -> Result<u64, Box<dyn Error>> {
+        let path = Path::new(&self.archive_dir).join(name).join(archived.file_name());
+        if !path.exists() {
+            return Err(format!("chunk {} of topic {} is not archived", archived.chunk, name).into());
+        }
+        let data = read_archived(&path, archived)
+            .ok_or_else(|| format!("archived chunk {} of topic {} is corrupted", archived.chunk, name))?;
+        if archived.messages == 0 {
+            return Ok(0);
+        }
+
+        // Records as written by `Writer`, a length and a timestamp ahead of the message.
+        let mut batch: Vec<&[u8]> = Vec::with_capacity(archived.messages as usize);
+        let mut position = 0;
+        for _ in 0..archived.messages {
+            let len = data.get(position..position + 4).ok_or("archived chunk is truncated")?;
+            let len = u32::from_ne_bytes(len.try_into()?) as usize;
+            batch.push(data.get(position + 12..position + 12 + len).ok_or("archived chunk is truncated")?);
+            position += 12 + len;
+        }
+        Ok(self.producer(into, None)?.push_back_batch(&batch)?.len() as u64)
+    }
+
+    /// Producer and consumer databases of a topic, `None` if it does not exist.
+    pub(crate) fn topic_dbs(&self, txn: &RoTxn, name: &str) -> Result<Option<(ProducerDb, ConsumerDb)>, Box<dyn Error>> {
+        let producer_db: Option<ProducerDb> = self.open_db(txn, &format!("{}_{}", name, "producer"))?;
+        let consumer_db: Option<ConsumerDb> = self.open_db(txn, &format!("{}_{}", name, "consumer"))?;
+        match (producer_db, consumer_db) {
+            (Some(producer_db), Some(consumer_db)) if !producer_db.is_empty(txn)? => Ok(Some((producer_db, consumer_db))),
+            _ => Ok(None),
+        }
+    }
+
+    fn chunk_paths(&self, txn: &RoTxn, name: &str, head: u64, tail: u64) -> Result<Vec<String>, Box<dyn Error>> {
+        (head..=tail).map(|file_num| self.chunk_path_in(txn, name, file_num)).collect()
+    }
+
+    /// Starts a write transaction; the map cannot grow while it is held.
+    ///
+    /// The heed `RwTxn` is wrapped in a `Txn`, which derefs to it. Fails with `QueueError::ReadOnly` on a read only `Env`, which is what keeps producers,
+    /// consumers and topic management from touching anything there.
+    pub fn write_txn(&self) -> Result<Txn<'_, RwTxn<'_>>, Box<dyn Error>> {
+        if self.read_only {
+            return Err(QueueError::ReadOnly.into());
+        }
+        self.begin(|| self.lmdb_env.write_txn())
+    }
+
+    /// Starts a read transaction; the map cannot grow while it is held.
+    ///
+    /// Like `write_txn`, the heed `RoTxn` is wrapped in a `Txn`.
+    pub fn read_txn(&self) -> Result<Txn<'_, RoTxn<'_, WithTls>>, Box<dyn Error>> {
+        self.begin(|| self.lmdb_env.read_txn())
+    }
+
+    /// Starts a transaction, first taking over the map size if another process has grown the map.
+    fn begin<T>(&self, begin: impl Fn() -> heed3::Result<T>) -> Result<Txn<'_, T>, Box<dyn Error>> {
+        loop {
+            let resize_guard = self.resize_gate.read().unwrap();
+            match begin() {
+                Ok(txn) => return Ok(Txn { txn, _resize_guard: resize_guard }),
+                Err(heed3::Error::Mdb(heed3::MdbError::MapResized)) => {
+                    drop(resize_guard);
+                    let Some(_gate) = self.lock_resize_gate() else {
+                        return Err(heed3::Error::Mdb(heed3::MdbError::MapResized).into());
+                    };
+                    // A size of 0 makes lmdb adopt the size the map has on disk.
+                    unsafe { self.lmdb_env.resize(0)? };
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Writes the claimed chunk file `source` to `target` in the archive, compressed if `archived` says
+/// so. The file only appears under its name once complete.
+///
+/// A file already at `target` is kept if it holds the same chunk, left by an attempt cut short
+/// after writing it; anything else there is refused rather than overwritten.
+fn store_archived(source: &Path, target: &Path, archived: &ArchivedChunk) -> Result<(), Box<dyn Error>> {
+    if target.exists() {
+        return match read_archived(target, archived) {
+            Some(_) => Ok(()),
+            None => Err(format!("refusing to overwrite archived file {}", target.display()).into()),
+        };
+    }
+
+    // Hard links fail rather than replace an existing file, unlike renames.
+    if !archived.compressed {
+        match std::fs::hard_link(source, target) {
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {},
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return store_archived(source, target, archived),
+            result => return Ok(result?),
+        }
+    }
+
+    let staging = PathBuf::from(format!("{}.{}.tmp", target.display(), std::process::id()));
+    let written = match archived.compressed {
+        false => std::fs::copy(source, &staging).map(|_| ()),
+        true => compress(source, &staging),
+    };
+    let linked = written.and_then(|_| std::fs::hard_link(&staging, target));
+    std::fs::remove_file(&staging).ok();
+    match linked {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => store_archived(source, target, archived),
+        result => Ok(result?),
+    }
+}
+
+/// Contents of an archived chunk file, decompressed, `None` unless they match the checksum of `archived`.
+fn read_archived(path: &Path, archived: &ArchivedChunk) -> Option<Vec<u8>> {
+    let mut data = std::fs::read(path).ok()?;
+    if archived.compressed {
+        data = decompress(&data).ok()?;
+    }
+    if (data.len() as u64) < archived.bytes {
+        return None;
+    }
+    data.truncate(archived.bytes as usize);
+    (writer::crc32(0, &data) == archived.checksum).then_some(data)
+}
+
+#[cfg(feature = "compression")]
+fn compress(source: &Path, target: &Path) -> std::io::Result<()> {
+    let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(target)?, flate2::Compression::default());
+    std::io::copy(&mut std::fs::File::open(source)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_source: &Path, _target: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::other("archive compression needs the compression feature"))
+}
+
+#[cfg(feature = "compression")]
+fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut decompressed = vec![];
+    flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_data: &[u8]) -> std::io::Result<Vec<u8>> {
+    Err(std::io::Error::other("archive compression needs the compression feature"))
+}
+
+/// Adds an archived chunk to the manifest in `dir` unless it is listed already.
+///
+/// The manifest is locked meanwhile, so processes archiving the same chunk list it once.
+fn list_archived(dir: &Path, mut archived: ArchivedChunk) -> Result<(), Box<dyn Error>> {
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+
+    let mut manifest = std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(dir.join("MANIFEST"))?;
+    // Released when the file is closed.
+    if unsafe { libc::flock(manifest.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut lines = String::new();
+    manifest.read_to_string(&mut lines)?;
+    // A line torn by a crash is left behind on its own rather than run into the next one.
+    if !lines.is_empty() && !lines.ends_with('\n') {
+        manifest.write_all(b"\n")?;
+    }
+    let file_name = archived.file_name();
+    if lines.lines().filter_map(|line| ArchivedChunk::from_line(line).ok()).any(|listed| listed.file_name() == file_name) {
+        return Ok(());
+    }
+
+    archived.archived = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock went backwards")
+        .as_secs();
+    manifest.write_all(archived.to_line().as_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn test_single() -> Result<(), Box<dyn Error>> {
+    let env = Env::new("/tmp/foo_env", None, None)?;
+    let mut producer = env.producer("test", Some(16 *1024 * 1024))?;
+    for i in 0..1024*1024 {
+        producer.push_back(format!("{}", i).as_bytes())?;
+    }
+
+    let mut consumer = env.consumer("test", None)?;
+    let lag = consumer.lag()?;
+    println!("Current lag is: {}", lag);
+
+    let mut message_count = 0;
+    loop {
+        let item = consumer.pop_front()?;
+        if let Some(item) = item {
+            message_count += 1;
+            if message_count % (1024 * 100) == 0 {
+                println!("Got message: {}", String::from_utf8(item.data)?);
+                let cur_lag = consumer.lag()?;
+                assert!(lag == cur_lag + message_count as u64);
+            }
+        } else {
+            println!("Read {} messages.", message_count);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_batch() -> Result<(), Box<dyn Error>> {
+    let env = Env::new("/tmp/foo_env", None, None)?;
+    let mut producer = env.producer("test", Some(16 * 1024 * 1024))?;
+    for i in 0..1024*100 {
+        let vec: Vec<String> = (0..10).map(|v| format!("{}_{}", i, v)).collect();
+        let batch: Vec<&[u8]> = vec.iter().map(|v| v.as_bytes()).collect();
+
+        producer.push_back_batch(&batch)?;
+    }
+
+    let mut consumer = env.consumer("test", None)?;
+    let lag = consumer.lag()?;
+    println!("Current lag is: {}", lag);
+    let mut message_count = 0;
+    loop {
+        let items = consumer.pop_front_n(10)?;
+        if !items.is_empty() {
+            message_count += items.len();
+            if message_count % (1024 * 100) == 0 {
+                println!("Got message: {}", String::from_utf8(items[0].data.clone())?);
+                let cur_lag = consumer.lag()?;
+                assert!(lag == cur_lag + message_count as u64);
+            }
+        } else {
+            println!("Read {} messages.", message_count);
+            break;
+        }
+    }
+
+    Ok(())
+}
+#[cfg(test)]
+fn test_env(name: &str) -> Result<Env, Box<dyn Error>> {
+    let prefix = format!("lmdb_queue_{}", name);
+    for entry in std::fs::read_dir("/tmp")? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            match entry.file_type()?.is_dir() {
+                true => std::fs::remove_dir_all(entry.path())?,
+                false => std::fs::remove_file(entry.path())?,
+            }
+        }
+    }
+    Env::new(format!("/tmp/{}", prefix), None, None)
+}
+
+#[test]
+fn test_receipts() -> Result<(), Box<dyn Error>> {
+    let env = test_env("receipts")?;
+    let mut producer = env.producer("test", Some(1024))?;
+
+    let mut receipts = vec![];
+    for i in 0..100 {
+        let message = format!("message_{:03}", i);
+        receipts.push(producer.push_back(message.as_bytes())?);
+    }
+    let batch: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+    receipts.extend(producer.push_back_batch(&batch)?);
+
+    for (i, receipt) in receipts.iter().enumerate() {
+        assert_eq!(receipt.offset, i as u64);
+    }
+    assert!(receipts.last().unwrap().chunk > 0);
+    assert_eq!(receipts[1].position, receipts[0].position + 12 + 11);
+
+    let mut consumer = env.consumer("test", Some(1024))?;
+    let items = consumer.pop_front_n(50)?;
+    assert_eq!(items.iter().map(|item| item.offset).collect::<Vec<_>>(), (0..50).collect::<Vec<_>>());
+    let mut next_offset = 50;
+    while let Some(item) = consumer.pop_front()? {
+        assert_eq!(item.offset, next_offset);
+        next_offset += 1;
+    }
+    assert_eq!(next_offset, receipts.len() as u64);
+
+    Ok(())
+}
+
+#[test]
+fn test_idempotent_producer() -> Result<(), Box<dyn Error>> {
+    let env = test_env("idempotent")?;
+    {
+        let mut producer = env.idempotent_producer("test", "producer-1", None)?;
+        assert_eq!(producer.sequence()?, Some(0));
+        let batch: Vec<&[u8]> = vec![b"a", b"b"];
+        assert_eq!(producer.push_back_batch(&batch)?.len(), 2);
+        assert_eq!(producer.push_back_batch_with_sequence(0, &batch)?.len(), 0);
+        assert_eq!(producer.push_back_batch(&batch)?.len(), 2);
+    }
+
+    let mut producer = env.idempotent_producer("test", "producer-1", None)?;
+    assert_eq!(producer.sequence()?, Some(2));
+    assert_eq!(producer.push_back_batch_with_sequence(1, &[b"c".as_slice()])?.len(), 0);
+
+    let mut other = env.idempotent_producer("test", "producer-2", None)?;
+    assert_eq!(other.push_back_batch_with_sequence(1, &[b"d".as_slice()])?.len(), 1);
+
+    // Sequences only advance with the transaction that commits the batch, aborted ones leave no gap.
+    let mut transaction = env.transaction()?;
+    transaction.push_back_batch(&mut producer, &[b"e".as_slice()])?;
+    transaction.push_back_batch(&mut producer, &[b"f".as_slice()])?;
+    drop(transaction);
+    producer.recover()?;
+    assert_eq!(producer.sequence()?, Some(2));
+    let mut txn = env.write_txn()?;
+    producer.push_back_batch_in(&mut txn, &[b"e".as_slice()])?;
+    producer.push_back_batch_in(&mut txn, &[b"f".as_slice()])?;
+    txn.commit()?;
+    assert_eq!(producer.sequence()?, Some(4));
+    assert_eq!(producer.push_back_batch(&[b"g".as_slice()])?.len(), 1);
+    assert_eq!(producer.sequence()?, Some(5));
+
+    // A failed call keeps its sequence: the retry is written if the batch was not committed, and
+    // dropped if the failure came after the commit, done here with an explicit sequence.
+    env.alter_topic("test", &TopicConfig { quota_messages: Some(6), ..Default::default() })?;
+    assert!(producer.push_back_batch(&[b"h".as_slice()]).is_err());
+    assert_eq!(producer.sequence()?, Some(5));
+    env.alter_topic("test", &TopicConfig::default())?;
+    assert_eq!(producer.push_back_batch(&[b"h".as_slice()])?.len(), 1);
+    env.alter_topic("test", &TopicConfig { quota_messages: Some(6), ..Default::default() })?;
+    assert!(producer.push_back_batch(&[b"i".as_slice()]).is_err());
+    env.alter_topic("test", &TopicConfig::default())?;
+    assert_eq!(producer.push_back_batch_with_sequence(6, &[b"i".as_slice()])?.len(), 1);
+    assert_eq!(producer.push_back_batch(&[b"i".as_slice()])?.len(), 0);
+    assert_eq!(producer.sequence()?, Some(7));
+
+    let consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.lag()?, 10);
+
+    Ok(())
+}
+
+#[test]
+fn test_dedup_window() -> Result<(), Box<dyn Error>> {
+    use super::error::QueueError;
+    use super::config::DedupWindow;
+
+    let env = test_env("dedup")?;
+    env.create_topic("test", &TopicConfig { dedup_window: Some(DedupWindow::Count(2)), ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+
+    producer.push_back(b"a")?;
+    let err = producer.push_back(b"a").unwrap_err();
+    assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::Duplicate)));
+
+    let batch: Vec<&[u8]> = vec![b"a", b"b", b"b", b"c"];
+    let receipts = producer.push_back_batch(&batch)?;
+    assert_eq!(receipts.iter().map(|r| (r.offset, r.index)).collect::<Vec<_>>(), vec![(1, 1), (2, 3)]);
+
+    // "a" fell out of the two message window.
+    assert_eq!(producer.push_back(b"a")?.offset, 3);
+
+    producer.push_back_with_key(b"key", b"d")?;
+    assert!(producer.push_back_with_key(b"key", b"e").is_err());
+
+    // Keyed pushes of an idempotent producer are tagged with its sequence as well.
+    let mut producer = env.idempotent_producer("test", "producer-1", None)?;
+    producer.push_back_with_key(b"other", b"f")?;
+    assert_eq!(producer.sequence()?, Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn test_publish() -> Result<(), Box<dyn Error>> {
+    let env = test_env("publish")?;
+    let mut orders = env.producer("orders", None)?;
+    let mut audit = env.producer("audit", None)?;
+
+    let order: Vec<&[u8]> = vec![b"order"];
+    let entries: Vec<&[u8]> = vec![b"created", b"paid"];
+    let receipts = env.publish(&mut [(&mut orders, &order), (&mut audit, &entries)])?;
+    assert_eq!(receipts[0].len(), 1);
+    assert_eq!(receipts[1].iter().map(|r| r.offset).collect::<Vec<_>>(), vec![0, 1]);
+
+    let mut orders_consumer = env.consumer("orders", None)?;
+    let mut audit_consumer = env.consumer("audit", None)?;
+    assert_eq!(orders_consumer.pop_front()?.unwrap().data, b"order");
+    assert_eq!(audit_consumer.pop_front_n(10)?.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_transaction() -> Result<(), Box<dyn Error>> {
+    let env = test_env("transaction")?;
+    let mut input = env.producer("input", None)?;
+    let mut output = env.producer("output", None)?;
+    input.push_back(b"1")?;
+    input.push_back(b"2")?;
+
+    let mut stage = env.consumer("input", None)?;
+    {
+        let mut transaction = env.transaction()?;
+        let item = transaction.pop_front(&mut stage)?.unwrap();
+        transaction.push_back(&mut output, &item.data)?;
+        // Dropped without commit: neither the pop nor the push happened.
+    }
+
+    let mut transaction = env.transaction()?;
+    let item = transaction.pop_front(&mut stage)?.unwrap();
+    assert_eq!(item.data, b"1");
+    let receipt = transaction.push_back(&mut output, &item.data)?;
+    assert_eq!((receipt.offset, receipt.position), (0, 0));
+    transaction.commit()?;
+
+    let mut sink = env.consumer("output", None)?;
+    assert_eq!(sink.pop_front_n(10)?.iter().map(|item| item.data.clone()).collect::<Vec<_>>(), vec![b"1".to_vec()]);
+    assert_eq!(stage.pop_front()?.unwrap().data, b"2");
+
+    Ok(())
+}
+
+#[test]
+fn test_outbox() -> Result<(), Box<dyn Error>> {
+    use heed3::types::Str;
+
+    let env = test_env("outbox")?;
+    let mut producer = env.producer("events", None)?;
+    let mut consumer = env.consumer("events", None)?;
+
+    let mut txn = env.write_txn()?;
+    let orders: heed3::Database<Str, Str> = env.db(&mut txn, "orders")?;
+    orders.put(&mut txn, "42", "created")?;
+    producer.push_back_batch_in(&mut txn, &[b"order 42 created".as_slice()])?;
+    txn.commit()?;
+
+    let mut txn = env.write_txn()?;
+    let item = consumer.pop_front_in(&mut txn)?.unwrap();
+    assert_eq!(item.data, b"order 42 created");
+    assert_eq!(orders.get(&txn, "42")?, Some("created"));
+    drop(txn);
+
+    let mut txn = env.write_txn()?;
+    assert_eq!(consumer.pop_front_in(&mut txn)?.unwrap().offset, 0);
+    assert!(consumer.pop_front_in(&mut txn)?.is_none());
+    txn.commit()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_topic_management() -> Result<(), Box<dyn Error>> {
+    let env = test_env("management")?;
+    let mut producer = env.producer("kept", Some(64))?;
+    for i in 0..10 {
+        producer.push_back(format!("message_{}", i).as_bytes())?;
+    }
+    let mut dropped = env.producer("dropped", Some(16))?;
+    for i in 0..3 {
+        dropped.push_back(format!("message-{:012}", i).as_bytes())?;
+    }
+
+    let mut topics = env.topics()?;
+    topics.sort();
+    assert_eq!(topics, vec!["dropped", "kept"]);
+
+    let info = env.topic_info("kept")?.unwrap();
+    assert_eq!((info.messages, info.head_offset, info.tail_offset), (10, 0, 10));
+    assert!(info.chunks > 1);
+    assert!(env.topic_info("missing")?.is_none());
+
+    // Chunk 0 is left on the garbage list, as if the consumer had died right after its commit.
+    let first = env.chunk_path("dropped", 0)?;
+    let mut consumer = env.consumer("dropped", None)?;
+    let mut txn = env.write_txn()?;
+    assert_eq!(consumer.pop_front_n_in(&mut txn, 2)?.len(), 2);
+    txn.commit()?;
+    assert!(Path::new(&first).exists());
+
+    env.delete_topic("dropped")?;
+    assert_eq!(env.topics()?, vec!["kept"]);
+    assert!(!Path::new(&first).exists());
+    assert!(!Path::new(&env.chunk_path("dropped", 2)?).exists());
+
+    // Handles left open on the deleted topic fail instead of panicking.
+    let err = dropped.push_back(b"gone").unwrap_err();
+    assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::TopicNotFound(_))));
+    let err = consumer.pop_front().err().unwrap();
+    assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::TopicNotFound(_))));
+
+    let mut consumer = env.consumer("kept", None)?;
+    consumer.pop_front()?;
+    env.purge_topic("kept")?;
+    assert!(!Path::new(&env.chunk_path("kept", info.head_chunk)?).exists());
+    assert!(consumer.pop_front()?.is_none());
+
+    assert_eq!(producer.push_back(b"after purge")?.offset, 10);
+    let item = consumer.pop_front()?.unwrap();
+    assert_eq!((item.offset, item.data.as_slice()), (10, b"after purge".as_slice()));
+
+    Ok(())
+}
+
+#[test]
+fn test_create_topic() -> Result<(), Box<dyn Error>> {
+    use std::time::Duration;
+
+    let env = test_env("create")?;
+    let err = env.consumer("events", None).err().unwrap();
+    assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::TopicNotFound(_))));
+
+    env.create_topic("events", &TopicConfig { chunk_size: 1024, chunks_to_keep: 2, ..Default::default() })?;
+    assert!(env.create_topic("events", &TopicConfig::default()).is_err());
+
+    let mut consumer = env.consumer("events", None)?;
+    assert!(consumer.pop_front()?.is_none());
+    assert!(consumer.pop_front_wait(Duration::from_millis(20))?.is_none());
+
+    std::thread::scope(|scope| -> Result<(), Box<dyn Error>> {
+        scope.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            env.producer("events", None).unwrap().push_back(b"hello").unwrap();
+        });
+        let item = consumer.pop_front_wait(Duration::from_secs(10))?.unwrap();
+        assert_eq!(item.data, b"hello");
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[test]
+fn test_alter_topic() -> Result<(), Box<dyn Error>> {
+    let env = test_env("alter")?;
+    let err = env.alter_topic("test", &TopicConfig::default()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::TopicNotFound(_))));
+
+    let mut producer = env.producer("test", Some(1024))?;
+    assert_eq!(env.topic_config("test")?.chunk_size, 1024);
+    // Handle arguments only apply on creation.
+    env.producer("test", Some(4096))?;
+    env.consumer("test", Some(3))?;
+    assert_eq!(env.topic_config("test")?, TopicConfig { chunk_size: 1024, ..Default::default() });
+
+    // Topics from before configurations were stored take them from the first handle passing them.
+    let mut txn = env.write_txn()?;
+    let config_db: Database<Str, U64<BE>> = env.open_db(&txn, "test_config")?.unwrap();
+    config_db.clear(&mut txn)?;
+    txn.commit()?;
+    env.consumer("test", Some(3))?;
+    env.producer("test", Some(4096))?;
+    env.producer("test", Some(8192))?;
+    assert_eq!(env.topic_config("test")?, TopicConfig { chunk_size: 4096, chunks_to_keep: 3, ..Default::default() });
+
+    let config = TopicConfig { chunk_size: 16, ttl: None, ..Default::default() };
+    env.alter_topic("test", &config)?;
+    assert_eq!(env.topic_config("test")?, config);
+
+    // The open producer rotates with the new chunk size.
+    for _ in 0..4 {
+        producer.push_back(b"0123456789abcdef0123")?;
+    }
+    assert_eq!(env.topic_info("test")?.unwrap().chunks, 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_retention() -> Result<(), Box<dyn Error>> {
+    let env = test_env("retention")?;
+    env.create_topic("test", &TopicConfig { chunk_size: 16, chunks_to_keep: 100, max_bytes: Some(64), ..Default::default() })?;
+
+    // Every 32 byte record ends up in a chunk of its own, only two of them fit in 64 bytes.
+    let mut producer = env.producer("test", None)?;
+    for i in 0..10 {
+        producer.push_back(format!("message-{:012}", i).as_bytes())?;
+    }
+    let info = env.topic_info("test")?.unwrap();
+    assert_eq!((info.chunks, info.bytes, info.head_offset, info.tail_offset), (2, 64, 8, 10));
+    assert!(!std::path::Path::new(&env.chunk_path("test", info.head_chunk - 1)?).exists());
+
+    // Without any producer or consumer around, the janitor drops the chunk gone stale.
+    env.alter_topic("test", &TopicConfig { chunk_size: 16, chunks_to_keep: 100, max_age: Some(60), ..Default::default() })?;
+    let mut txn = env.write_txn()?;
+    let catalog_db: Database<U64<BE>, Bytes> = env.open_db(&txn, "test_chunks")?.unwrap();
+    let head = ChunkInfo::decode(info.head_chunk, catalog_db.get(&txn, &info.head_chunk)?.unwrap())?;
+    catalog_db.put(&mut txn, &info.head_chunk, &ChunkInfo { last_ts: head.last_ts - 3600, ..head }.encode())?;
+    txn.commit()?;
+    drop(producer);
+    assert_eq!(env.enforce_retention()?, 1);
+    assert_eq!(env.enforce_retention()?, 0);
+
+    let mut consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.pop_front()?.unwrap().offset, 9);
+    assert!(consumer.pop_front()?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_ttl() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::FileExt;
+
+    let env = test_env("ttl")?;
+    env.create_topic("test", &TopicConfig { ttl: Some(60), ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    let batch: Vec<&[u8]> = vec![b"0", b"1", b"2", b"3", b"4"];
+    let receipts = producer.push_back_batch(&batch)?;
+
+    // Message 1 expired long ago, message 3 comes from a producer whose clock runs ahead.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let file = std::fs::OpenOptions::new().write(true).open(env.chunk_path("test", 0)?)?;
+    file.write_at(&(now - 3600).to_ne_bytes(), receipts[1].position + 4)?;
+    file.write_at(&(now + 3600).to_ne_bytes(), receipts[3].position + 4)?;
+
+    let mut consumer = env.consumer("test", None)?;
+    let items = consumer.pop_front_n(2)?;
+    assert_eq!(items.iter().map(|item| item.offset).collect::<Vec<_>>(), vec![0, 2]);
+    let items = consumer.pop_front_n(10)?;
+    assert_eq!(items.iter().map(|item| (item.offset, item.data.clone())).collect::<Vec<_>>(), vec![(3, b"3".to_vec()), (4, b"4".to_vec())]);
+    assert!(consumer.pop_front()?.is_none());
+
+    // The reader stays in step with the committed position afterwards.
+    producer.push_back(b"5")?;
+    assert_eq!(consumer.pop_front()?.unwrap().data, b"5");
+    assert_eq!(consumer.lag()?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_quota() -> Result<(), Box<dyn Error>> {
+    use std::time::Duration;
+    use super::config::QuotaPolicy;
+
+    let env = test_env("quota")?;
+    env.create_topic("test", &TopicConfig { quota_lag: Some(2), ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    let mut consumer = env.consumer("test", None)?;
+
+    producer.push_back_batch(&[b"a".as_slice(), b"b".as_slice()])?;
+    let err = producer.push_back(b"c").unwrap_err();
+    assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::QuotaExceeded(_))));
+    consumer.pop_front()?;
+    assert_eq!(producer.push_back(b"c")?.offset, 2);
+
+    // A blocked producer resumes once the consumer catches up.
+    env.alter_topic("test", &TopicConfig { quota_lag: Some(2), quota_policy: QuotaPolicy::Block(Duration::from_secs(10)), ..Default::default() })?;
+    std::thread::scope(|scope| -> Result<(), Box<dyn Error>> {
+        scope.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            consumer.pop_front().unwrap();
+        });
+        assert_eq!(producer.push_back(b"d")?.offset, 3);
+        Ok(())
+    })?;
+
+    // New records get a chunk of their own, "f" only fits once the first chunk with four is dropped.
+    env.alter_topic("test", &TopicConfig { chunk_size: 1, quota_messages: Some(5), quota_policy: QuotaPolicy::DropOldest, ..Default::default() })?;
+    for message in [b"e", b"f", b"g"] {
+        producer.push_back(message)?;
+    }
+    let info = env.topic_info("test")?.unwrap();
+    assert_eq!((info.messages, info.head_offset, info.tail_offset), (3, 4, 7));
+
+    Ok(())
+}
+
+#[test]
+fn test_capacity() -> Result<(), Box<dyn Error>> {
+    let env = test_env("capacity")?;
+    env.create_topic("test", &TopicConfig { chunk_size: 40, capacity: Some(3), ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    let mut consumer = env.consumer("test", None)?;
+
+    for i in 0..10u8 {
+        producer.push_back(&[i])?;
+    }
+    assert_eq!(consumer.lag()?, 3);
+    let item = consumer.pop_front()?.unwrap();
+    assert_eq!((item.offset, item.data), (7, vec![7]));
+
+    // The producer overtakes the consumer in the middle of a chunk.
+    for i in 10..15u8 {
+        producer.push_back(&[i])?;
+    }
+    assert_eq!(consumer.lag()?, 3);
+    let items = consumer.pop_front_n(10)?;
+    assert_eq!(items.iter().map(|item| (item.offset, item.data[0])).collect::<Vec<_>>(), vec![(12, 12), (13, 13), (14, 14)]);
+    assert!(env.topic_info("test")?.unwrap().chunks <= 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_map_growth() -> Result<(), Box<dyn Error>> {
+    use super::config::DedupWindow;
+
+    drop(test_env("grow")?);
+    let env = EnvOptions::new()
+        .map_size(256 * 1024)
+        .max_map_size(1024 * 1024)
+        .open("/tmp/lmdb_queue_grow")?;
+    env.create_topic("test", &TopicConfig { dedup_window: Some(DedupWindow::Count(u64::MAX)), ..Default::default() })?;
+
+    // Every message leaves a dedup entry behind, until the map cannot grow any further.
+    let mut producer = env.producer("test", None)?;
+    let mut written = 0;
+    let err = loop {
+        let batch: Vec<String> = (written..written + 100).map(|i| format!("{:016}", i)).collect();
+        let batch: Vec<&[u8]> = batch.iter().map(|message| message.as_bytes()).collect();
+        match producer.push_back_batch(&batch) {
+            Ok(receipts) => written += receipts.len() as u64,
+            Err(e) => break e,
+        }
+    };
+    assert!(is_map_full(&*err));
+    assert_eq!(env.map_resizes(), 2);
+    assert_eq!(env.lmdb_env.info().map_size, 1024 * 1024);
+    assert_eq!(env.topic_info("test")?.unwrap().tail_offset, written);
+
+    let mut consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.pop_front_n(written)?.len() as u64, written);
+
+    Ok(())
+}
+
+#[test]
+fn test_resize_nested_txn() -> Result<(), Box<dyn Error>> {
+    let env = test_env("resize")?;
+    env.create_topic("test", &TopicConfig::default())?;
+
+    // A resize waiting for this thread's read transaction does not hold off its write transaction.
+    let txn = env.read_txn()?;
+    std::thread::scope(|scope| -> Result<(), Box<dyn Error>> {
+        let resize = scope.spawn(|| env.grow_map().unwrap());
+        std::thread::sleep(Duration::from_millis(50));
+        let mut producer = env.producer("test", None)?;
+        producer.push_back(b"nested")?;
+        drop(txn);
+        assert!(resize.join().unwrap());
+        Ok(())
+    })?;
+    assert_eq!(env.map_resizes(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_env_options() -> Result<(), Box<dyn Error>> {
+    let root = "/tmp/lmdb_queue_options";
+    let data_dir = "/tmp/lmdb_queue_options_data";
+    std::fs::remove_dir_all(root).ok();
+    std::fs::remove_dir_all(data_dir).ok();
+
+    let env = EnvOptions::new()
+        .sub_dir(true)
+        .sync_mode(SyncMode::Sync)
+        .max_readers(16)
+        .data_dir(data_dir)
+        .topic_config(TopicConfig { chunk_size: 1024, ..Default::default() })
+        .open(root)?;
+    env.producer("test", None)?.push_back(b"hello")?;
+    assert_eq!(env.topic_config("test")?.chunk_size, 1024);
+    assert!(std::path::Path::new(root).join("data.mdb").exists());
+    assert!(env.chunk_path("test", 0)?.starts_with(data_dir));
+    assert!(std::path::Path::new(&env.chunk_path("test", 0)?).exists());
+    drop(env);
+
+    let env = EnvOptions::new().sub_dir(true).read_only(true).data_dir(data_dir).open(root)?;
+    assert_eq!(env.topic_info("test")?.unwrap().messages, 1);
+    assert!(env.write_txn().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_migrate_flat_layout() -> Result<(), Box<dyn Error>> {
+    drop(test_env("migrate")?);
+    std::fs::remove_dir_all("/tmp/lmdb_queue_migrate")?;
+    let root = "/tmp/lmdb_queue_migrate";
+
+    let env = EnvOptions::new().sub_dir(false).open(root)?;
+    env.producer("a", None)?.push_back(b"from a")?;
+    env.producer("a-b", None)?.push_back(b"from a-b")?;
+    assert!(std::path::Path::new("/tmp/lmdb_queue_migrate-a-b-0000000000000000").exists());
+    drop(env);
+
+    let env = Env::new(root, None, None)?;
+    assert!(std::path::Path::new(root).join("data.mdb").exists());
+    assert!(!std::path::Path::new("/tmp/lmdb_queue_migrate-a-0000000000000000").exists());
+    assert_eq!(env.chunk_path("a-b", 0)?, "/tmp/lmdb_queue_migrate/a-b/0000000000000000");
+    assert_eq!(env.consumer("a", None)?.pop_front()?.unwrap().data, b"from a");
+    assert_eq!(env.consumer("a-b", None)?.pop_front()?.unwrap().data, b"from a-b");
+
+    for name in ["../escape", "data.mdb", "lock.mdb"] {
+        let err = env.create_topic(name, &TopicConfig::default()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::InvalidTopicName(_))));
+    }
+    env.create_topic("orders-archive", &TopicConfig::default())?;
+
+    // Topics created before names were checked can still be produced to.
+    let mut txn = env.write_txn()?;
+    let producer_db: Database<U64<BE>, U64<BE>> = env.db(&mut txn, "old topic_producer")?;
+    let consumer_db: Database<Str, U64<BE>> = env.db(&mut txn, "old topic_consumer")?;
+    producer_db.put(&mut txn, &0, &0)?;
+    for key in [KEY_CONSUMER_FILE, KEY_CONSUMER_OFFSET, KEY_CONSUMER_BYTES_READ] {
+        consumer_db.put(&mut txn, key, &0)?;
+    }
+    txn.commit()?;
+    std::fs::create_dir_all(Path::new(root).join("old topic"))?;
+    env.producer("old topic", None)?.push_back(b"still there")?;
+    assert_eq!(env.consumer("old topic", None)?.pop_front()?.unwrap().data, b"still there");
+
+    Ok(())
+}
+
+#[test]
+fn test_data_dirs() -> Result<(), Box<dyn Error>> {
+    use super::options::Placement;
+
+    let root = "/tmp/lmdb_queue_stripe";
+    let dirs = ["/tmp/lmdb_queue_stripe_a", "/tmp/lmdb_queue_stripe_b"];
+    for dir in [root, dirs[0], dirs[1]] {
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    let env = EnvOptions::new().data_dirs(dirs).placement(Placement::PerChunk).open(root)?;
+    env.create_topic("test", &TopicConfig { chunk_size: 1, ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    for i in 0..4u8 {
+        producer.push_back(&[i])?;
+    }
+    for file_num in 0..4 {
+        assert_eq!(env.chunk_path("test", file_num)?, format!("{}/test/{:016x}", dirs[file_num as usize % 2], file_num));
+        assert!(Path::new(&env.chunk_path("test", file_num)?).exists());
+    }
+    drop(producer);
+    drop(env);
+
+    // Chunks are found where they were placed, whatever the directories are configured as now.
+    let env = EnvOptions::new().data_dirs([dirs[1], dirs[0]]).open(root)?;
+    let mut consumer = env.consumer("test", None)?;
+    let items = consumer.pop_front_n(10)?;
+    assert_eq!(items.iter().map(|item| item.data[0]).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+    consumer.pop_front()?;
+    assert!(!Path::new(&format!("{}/test/{:016x}", dirs[0], 0)).exists());
+    drop(consumer);
+    drop(env);
+
+    // A purged topic stays in its directory, and unknown chunks are not looked for anywhere.
+    let env = EnvOptions::new().data_dirs(dirs).open(root)?;
+    env.create_topic("other", &TopicConfig { chunk_size: 1, ..Default::default() })?;
+    let topic_dir = dirs[topic::fnv1a(b"other") as usize % 2];
+    env.producer("other", None)?.push_back_batch(&[b"a".as_slice(), b"b"])?;
+    env.purge_topic("other")?;
+    assert_eq!(env.chunk_path("other", 1)?, format!("{}/other/{:016x}", topic_dir, 1));
+    assert!(env.chunk_path("other", 7).is_err());
+    drop(env);
+
+    // With the root's parent as data directory, topics cannot take the names of the root, its
+    // staging and archive directories or the other data directories.
+    std::fs::remove_dir_all("/tmp/lmdb_queue_stripe-orders").ok();
+    let env = EnvOptions::new().data_dirs(["/tmp", dirs[0]]).open(root)?;
+    for name in ["lmdb_queue_stripe", "lmdb_queue_stripe.migrating", "lmdb_queue_stripe-archive", "lmdb_queue_stripe_a"] {
+        let err = env.create_topic(name, &TopicConfig::default()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<QueueError>(), Some(QueueError::InvalidTopicName(_))));
+    }
+    env.create_topic("lmdb_queue_stripe-orders", &TopicConfig::default())?;
+
+    Ok(())
+}
+
+#[test]
+fn test_read_only() -> Result<(), Box<dyn Error>> {
+    let env = test_env("read_only")?;
+    let mut producer = env.producer("test", Some(64))?;
+    for i in 0..10u8 {
+        producer.push_back(&[i; 16])?;
+    }
+    let mut consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.pop_front_n(3)?.len(), 3);
+    drop(consumer);
+    drop(producer);
+    let info = env.topic_info("test")?.unwrap();
+    drop(env);
+
+    let env = EnvOptions::new().read_only(true).open("/tmp/lmdb_queue_read_only")?;
+    assert!(env.is_read_only());
+    assert_eq!(env.topics()?, vec!["test".to_string()]);
+    assert_eq!(env.topic_info("test")?.unwrap(), info);
+
+    let items = env.peek("test", 4)?;
+    assert_eq!(items.iter().map(|item| (item.offset, item.data[0])).collect::<Vec<_>>(), vec![(3, 3), (4, 4), (5, 5), (6, 6)]);
+    assert_eq!(env.peek("test", 100)?.len(), 7);
+    assert!(matches!(env.peek("missing", 1).err().unwrap().downcast_ref::<QueueError>(), Some(QueueError::TopicNotFound(_))));
+
+    for e in [env.consumer("test", None).err().unwrap(), env.producer("test", None).err().unwrap(), env.purge_topic("test").unwrap_err()] {
+        assert!(matches!(e.downcast_ref::<QueueError>(), Some(QueueError::ReadOnly)));
+    }
+    assert_eq!(env.topic_info("test")?.unwrap(), info);
+    assert!(Path::new(&env.chunk_path("test", info.head_chunk)?).exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_scanner() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::FileExt;
+
+    let env = test_env("scanner")?;
+    let mut producer = env.producer("test", Some(64))?;
+    for i in 0..10u8 {
+        producer.push_back(&[i; 16])?;
+    }
+    let mut consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.pop_front_n(5)?.len(), 5);
+
+    let offsets = |scanner: TopicScanner| -> Result<Vec<u64>, Box<dyn Error>> {
+        scanner.map(|item| item.map(|item| item.offset)).collect()
+    };
+    // Consumed messages stay visible as long as their chunk is retained, the first chunk is gone.
+    assert_eq!(offsets(env.scanner("test")?)?, (3..10).collect::<Vec<_>>());
+    assert_eq!(offsets(env.scanner("test")?.offsets(1..8))?, (3..8).collect::<Vec<_>>());
+    assert_eq!(offsets(env.scanner("test")?.offsets(7..))?, (7..10).collect::<Vec<_>>());
+    assert_eq!(offsets(env.scanner("test")?.offsets(20..))?, Vec::<u64>::new());
+    let item = env.scanner("test")?.offsets(3..=3).next().unwrap()?;
+    assert_eq!((item.offset, item.data), (3, vec![3; 16]));
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    assert_eq!(offsets(env.scanner("test")?.timestamps(now - 60..))?.len(), 7);
+    assert_eq!(offsets(env.scanner("test")?.timestamps(..now - 60))?.len(), 0);
+    assert_eq!(offsets(env.scanner("test")?.timestamps(now - 60..).offsets(..5))?, (3..5).collect::<Vec<_>>());
+
+    // Appends after creation are not part of the scan, and the consumer is left where it was.
+    let scanner = env.scanner("test")?;
+    producer.push_back(&[10; 16])?;
+    assert_eq!(offsets(scanner)?.len(), 7);
+    assert_eq!(consumer.pop_front()?.unwrap().offset, 5);
+    assert!(matches!(env.scanner("missing").err().unwrap().downcast_ref::<QueueError>(), Some(QueueError::TopicNotFound(_))));
+
+    // A chunk started by a clock stepped back does not hide the messages of the one before.
+    env.create_topic("stepped", &TopicConfig { chunk_size: 1, ..Default::default() })?;
+    let mut producer = env.producer("stepped", None)?;
+    for i in 0..3u8 {
+        producer.push_back(&[i])?;
+    }
+    let file = std::fs::OpenOptions::new().write(true).open(env.chunk_path("stepped", 1)?)?;
+    file.write_at(&(now - 3600).to_ne_bytes(), 4)?;
+    env.rebuild_index("stepped")?;
+    assert_eq!(offsets(env.scanner("stepped")?.timestamps(now - 60..))?, vec![0, 2]);
+    assert_eq!(offsets(env.scanner("stepped")?.timestamps(..now - 60))?, vec![1]);
+
+    Ok(())
+}
+
+#[test]
+fn test_get() -> Result<(), Box<dyn Error>> {
+    let env = test_env("get")?;
+    let mut producer = env.producer("test", Some(4096))?;
+    let messages: Vec<Vec<u8>> = (0..2000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    let mut receipts = vec![];
+    for batch in messages.chunks(50) {
+        let batch: Vec<&[u8]> = batch.iter().map(|m| m.as_slice()).collect();
+        receipts.extend(producer.push_back_batch(&batch)?);
+    }
+    assert!(receipts.last().unwrap().chunk > 2);
+
+    for receipt in receipts.iter().step_by(97).chain(receipts.last()) {
+        let item = env.get("test", receipt.offset)?.unwrap();
+        assert_eq!((item.offset, item.data), (receipt.offset, (receipt.offset as u32).to_be_bytes().to_vec()));
+    }
+    assert!(env.get("test", 2000)?.is_none());
+
+    // Once the head chunk is consumed and dropped, its messages are gone.
+    let mut consumer = env.consumer("test", None)?;
+    let first_chunk = receipts.iter().filter(|receipt| receipt.chunk == 0).count() as u64;
+    consumer.pop_front_n(first_chunk + 1)?;
+    assert!(env.get("test", 0)?.is_none());
+    assert!(env.get("test", first_chunk - 1)?.is_none());
+    assert_eq!(env.get("test", first_chunk)?.unwrap().offset, first_chunk);
+    assert!(matches!(env.get("missing", 0).err().unwrap().downcast_ref::<QueueError>(), Some(QueueError::TopicNotFound(_))));
+
+    // A file missing from a chunk that is still retained is an error, not a dropped message.
+    let receipt = receipts.iter().find(|receipt| receipt.chunk == 2).unwrap();
+    std::fs::remove_file(env.chunk_path("test", 2)?)?;
+    assert!(env.get("test", receipt.offset).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_index() -> Result<(), Box<dyn Error>> {
+    let env = test_env("index")?;
+    env.create_topic("test", &TopicConfig { index_messages: Some(10), index_bytes: None, ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    for i in 0..95u32 {
+        producer.push_back(&i.to_be_bytes())?;
+    }
+    let index_len = |env: &Env| -> Result<u64, Box<dyn Error>> {
+        let txn = env.read_txn()?;
+        let index_db: Database<U64<BE>, Bytes> = env.open_db(&txn, "test_index")?.unwrap();
+        Ok(index_db.len(&txn)?)
+    };
+    assert_eq!(index_len(&env)?, 10);
+    assert_eq!(env.rebuild_index("test")?, 10);
+
+    // Every record is 16 bytes, so this indexes every 20th message.
+    env.alter_topic("test", &TopicConfig { index_messages: None, index_bytes: Some(320), ..Default::default() })?;
+    assert_eq!(env.rebuild_index("test")?, 5);
+    assert_eq!(env.get("test", 47)?.unwrap().data, 47u32.to_be_bytes());
+
+    // A lost index still allows lookups and is rebuilt by the next producer.
+    drop(producer);
+    let mut txn = env.write_txn()?;
+    let index_db: Database<U64<BE>, Bytes> = env.open_db(&txn, "test_index")?.unwrap();
+    index_db.clear(&mut txn)?;
+    txn.commit()?;
+    assert_eq!(env.get("test", 93)?.unwrap().data, 93u32.to_be_bytes());
+    let scanner = env.scanner("test")?.offsets(50..55);
+    assert_eq!(scanner.map(|item| item.map(|item| item.offset)).collect::<Result<Vec<_>, _>>()?, (50..55).collect::<Vec<_>>());
+    drop(env.producer("test", None)?);
+    assert_eq!(index_len(&env)?, 5);
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    let scanner = env.scanner("test")?.offsets(50..55).timestamps(now - 60..);
+    assert_eq!(scanner.map(|item| item.map(|item| item.offset)).collect::<Result<Vec<_>, _>>()?, (50..55).collect::<Vec<_>>());
+    assert_eq!(env.scanner("test")?.timestamps(now + 60..).count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_chunk_catalog() -> Result<(), Box<dyn Error>> {
+    let env = test_env("catalog")?;
+    let mut producer = env.producer("test", Some(64))?;
+    for i in 0..7u8 {
+        producer.push_back(&[i; 20])?;
+    }
+
+    // 32 byte records, three to a chunk before it grows past 64 bytes.
+    let chunks = env.chunks("test")?;
+    assert_eq!(chunks.iter().map(|info| (info.chunk, info.bytes, info.sealed)).collect::<Vec<_>>(), vec![(0, 96, true), (1, 96, true), (2, 32, false)]);
+    assert!(chunks.iter().all(|info| info.first_ts > 0 && info.first_ts <= info.last_ts && info.created <= info.first_ts));
+    assert_eq!(env.topic_info("test")?.unwrap().bytes, 224);
+    for info in &chunks {
+        assert!(env.verify_chunk("test", info.chunk)?);
+    }
+
+    // Bytes of an aborted append are not covered, corruption of committed ones is detected.
+    let mut txn = env.write_txn()?;
+    producer.push_back_batch_in(&mut txn, &[[9u8; 20].as_slice()])?;
+    drop(txn);
+    assert!(env.verify_chunk("test", 2)?);
+    let mut data = std::fs::read(env.chunk_path("test", 1)?)?;
+    data[40] ^= 0xff;
+    std::fs::write(env.chunk_path("test", 1)?, data)?;
+    assert!(!env.verify_chunk("test", 1)?);
+
+    // Consumed chunks leave the catalog, and a lost catalog is rebuilt by the next producer.
+    let mut consumer = env.consumer("test", None)?;
+    consumer.pop_front_n(4)?;
+    drop(producer);
+    assert_eq!(env.chunks("test")?.iter().map(|info| info.chunk).collect::<Vec<_>>(), vec![1, 2]);
+    let mut txn = env.write_txn()?;
+    let catalog_db: Database<U64<BE>, Bytes> = env.open_db(&txn, "test_chunks")?.unwrap();
+    catalog_db.clear(&mut txn)?;
+    txn.commit()?;
+    let producer = env.producer("test", None)?;
+    let rebuilt = env.chunks("test")?;
+    assert_eq!(rebuilt.iter().map(|info| (info.bytes, info.sealed, info.first_ts, info.last_ts)).collect::<Vec<_>>(),
+        chunks[1..].iter().map(|info| (info.bytes, info.sealed, info.first_ts, info.last_ts)).collect::<Vec<_>>());
+    assert_eq!(rebuilt[1].checksum, chunks[2].checksum);
+    drop(producer);
+
+    Ok(())
+}
+
+#[test]
+fn test_rotation() -> Result<(), Box<dyn Error>> {
+    let env = test_env("rotation")?;
+    env.create_topic("test", &TopicConfig { chunk_messages: Some(3), ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    for i in 0..7u8 {
+        producer.push_back(&[i])?;
+    }
+    let counts = |env: &Env| -> Result<Vec<u64>, Box<dyn Error>> {
+        let txn = env.read_txn()?;
+        let (producer_db, _) = env.topic_dbs(&txn, "test")?.unwrap();
+        producer_db.iter(&txn)?.map(|entry| Ok(entry?.1)).collect()
+    };
+    assert_eq!(counts(&env)?, vec![3, 3, 1]);
+
+    let age_tail = |env: &Env| -> Result<(), Box<dyn Error>> {
+        let mut txn = env.write_txn()?;
+        let catalog_db: Database<U64<BE>, Bytes> = env.open_db(&txn, "test_chunks")?.unwrap();
+        let (tail, entry) = catalog_db.last(&txn)?.unwrap();
+        let info = ChunkInfo::decode(tail, entry)?;
+        catalog_db.put(&mut txn, &tail, &ChunkInfo { first_ts: info.first_ts - 3600, last_ts: info.last_ts - 3600, ..info }.encode())?;
+        txn.commit()?;
+        Ok(())
+    };
+    env.alter_topic("test", &TopicConfig { chunk_age: Some(60), max_age: Some(60), ..Default::default() })?;
+    age_tail(&env)?;
+    producer.push_back(&[7])?;
+    assert_eq!(counts(&env)?, vec![3, 3, 1, 1]);
+
+    // With nobody writing, the janitor rotates the stale tail and retention then drops it.
+    let mut consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.pop_front_n(8)?.len(), 8);
+    age_tail(&env)?;
+    assert_eq!(env.enforce_retention()?, 1);
+    assert_eq!(counts(&env)?, vec![0]);
+    assert!(consumer.pop_front()?.is_none());
+
+    producer.push_back(&[8])?;
+    assert_eq!(counts(&env)?, vec![1]);
+    assert_eq!(consumer.pop_front()?.unwrap().data, vec![8]);
+
+    Ok(())
+}
+
+#[test]
+fn test_punch_holes() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let env = test_env("punch")?;
+    env.create_topic("test", &TopicConfig { chunk_size: 1024 * 1024, punch_holes: Some(16 * 1024), ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    for i in 0..1000u32 {
+        producer.push_back(&[i.to_be_bytes().as_slice(), &[0; 96]].concat())?;
+    }
+    let path = env.chunk_path("test", 0)?;
+    let metadata = std::fs::metadata(&path)?;
+    assert_eq!(metadata.len(), 112 * 1000);
+
+    // 56000 bytes consumed, of which the whole blocks are given back.
+    let mut consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.pop_front_n(500)?.len(), 500);
+    let punched = std::fs::metadata(&path)?;
+    assert_eq!(punched.len(), metadata.len());
+    assert!(punched.blocks() < metadata.blocks());
+
+    assert!(env.get("test", 499)?.is_none());
+    assert_eq!(env.get("test", 500)?.unwrap().data[..4], 500u32.to_be_bytes());
+    assert_eq!(env.scanner("test")?.next().unwrap()?.offset, 500);
+    assert!(env.verify_chunk("test", 0).is_err());
+    assert_eq!(env.rebuild_index("test")?, 2);
+
+    // Pops through a transaction punch on commit, the consumer still reads every message.
+    let scanners = [env.scanner("test")?, env.scanner("test")?.offsets(600..)];
+    let mut transaction = env.transaction()?;
+    assert_eq!(transaction.pop_front_n(&mut consumer, 300)?.len(), 300);
+    transaction.commit()?;
+    assert!(std::fs::metadata(&path)?.blocks() < punched.blocks());
+    assert!(env.get("test", 700)?.is_none());
+
+    // Scanners created before the hole go on past the messages reclaimed since.
+    for scanner in scanners {
+        let items = scanner.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(items.iter().map(|item| item.offset).collect::<Vec<_>>(), (800..1000).collect::<Vec<_>>());
+        assert!(items.iter().all(|item| item.data[..4] == (item.offset as u32).to_be_bytes()));
+    }
+    assert_eq!(env.peek("test", 1)?[0].offset, 800);
+    let items = consumer.pop_front_n(1000)?;
+    assert_eq!(items.iter().map(|item| item.offset).collect::<Vec<_>>(), (800..1000).collect::<Vec<_>>());
+    assert!(items.iter().all(|item| item.data[..4] == (item.offset as u32).to_be_bytes()));
+
+    // Records of 109 bytes, the hole ends at block 28672 five bytes into the header of message
+    // 263, whose timestamp is left non-zero.
+    env.create_topic("straddle", &TopicConfig { chunk_size: 1024 * 1024, punch_holes: Some(16 * 1024), ..Default::default() })?;
+    let mut producer = env.producer("straddle", None)?;
+    for i in 0..400u32 {
+        producer.push_back(&[i.to_be_bytes().as_slice(), &[1; 93]].concat())?;
+    }
+    let mut scanner = env.scanner("straddle")?;
+    assert_eq!(scanner.by_ref().take(263).count(), 263);
+    let mut consumer = env.consumer("straddle", None)?;
+    assert_eq!(consumer.pop_front_n(300)?.len(), 300);
+    let item = scanner.next().unwrap()?;
+    assert_eq!((item.offset, &item.data[..4]), (300, 300u32.to_be_bytes().as_slice()));
+
+    Ok(())
+}
+
+#[test]
+fn test_orphans()-> Result<(), Box<dyn Error>> {
+    let env = test_env("orphans")?;
+    env.create_topic("test", &TopicConfig { chunk_size: 16, ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    for i in 0..4 {
+        producer.push_back(format!("message-{:012}", i).as_bytes())?;
+    }
+    drop(producer);
+
+    // The handle dies right after its pops commit, before removing the chunks they dropped.
+    let mut consumer = env.consumer("test", None)?;
+    let mut txn = env.write_txn()?;
+    assert_eq!(consumer.pop_front_n_in(&mut txn, 3)?.len(), 3);
+    txn.commit()?;
+    drop(consumer);
+    let dropped: Vec<String> = (0..2).map(|file_num| env.chunk_path_at(&env.data_dirs[0], "test", file_num)).collect();
+    assert!(dropped.iter().all(|path| Path::new(path).exists()));
+
+    // Chunk 0 is left out of the garbage list, it only lies below the head.
+    let mut txn = env.write_txn()?;
+    let garbage_db: Database<U64<BE>, Str> = env.open_db(&txn, "test_garbage")?.unwrap();
+    assert_eq!(garbage_db.len(&txn)?, 2);
+    garbage_db.delete(&mut txn, &0)?;
+    txn.commit()?;
+    drop(env);
+
+    let env = Env::new("/tmp/lmdb_queue_orphans", None, None)?;
+    let garbage_db: Database<U64<BE>, Str> = env.open_db(&*env.read_txn()?, "test_garbage")?.unwrap();
+    assert!(dropped.iter().all(|path| !Path::new(path).exists()));
+    assert!(Path::new(&env.chunk_path("test", 2)?).exists());
+    assert!(garbage_db.is_empty(&*env.read_txn()?)?);
+    assert_eq!(env.consumer("test", None)?.pop_front()?.unwrap().offset, 3);
+    // Chunks dropped by a pop leave the list along with their files.
+    assert!(!Path::new(&env.chunk_path_at(&env.data_dirs[0], "test", 2)).exists());
+    assert!(garbage_db.is_empty(&*env.read_txn()?)?);
+
+    // The janitor sweeps the list as well.
+    std::fs::write(&dropped[0], b"")?;
+    let mut txn = env.write_txn()?;
+    garbage_db.put(&mut txn, &0, &dropped[0])?;
+    txn.commit()?;
+    assert_eq!(env.enforce_retention()?, 0);
+    assert!(!Path::new(&dropped[0]).exists());
+    assert!(garbage_db.is_empty(&*env.read_txn()?)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_archive() -> Result<(), Box<dyn Error>> {
+    let env = test_env("archive")?;
+    env.create_topic("test", &TopicConfig { chunk_size: 16, archive: true, punch_holes: Some(1), ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    for i in 0..6 {
+        producer.push_back(format!("message-{:012}", i).as_bytes())?;
+    }
+    let checksums: Vec<u32> = env.chunks("test")?.iter().map(|info| info.checksum).collect();
+
+    // Consumed chunks are moved rather than removed, holes are not punched.
+    let mut consumer = env.consumer("test", None)?;
+    assert_eq!(consumer.pop_front_n(3)?.len(), 3);
+    let txn = env.read_txn()?;
+    assert_eq!(topic::head_start(&txn, env.topic_dbs(&txn, "test")?.unwrap().1)?, (0, 0));
+    drop(txn);
+    let archived = env.archived("test")?;
+    assert_eq!(archived.iter().map(|archived| (archived.chunk, archived.first_offset, archived.messages)).collect::<Vec<_>>(), vec![(0, 0, 1), (1, 1, 1)]);
+    assert_eq!(archived[1].checksum, checksums[1]);
+    assert!(archived.iter().all(|archived| archived.archived > 0 && archived.bytes == 32));
+    assert!(!Path::new(&env.chunk_path_at(&env.data_dirs[0], "test", 0)).exists());
+    assert!(Path::new("/tmp/lmdb_queue_archive-archive/test").join(archived[1].file_name()).exists());
+
+    // A chunk dropped right before a crash is archived by the next sweep.
+    let mut txn = env.write_txn()?;
+    assert_eq!(consumer.pop_front_n_in(&mut txn, 1)?.len(), 1);
+    txn.commit()?;
+    drop(consumer);
+    assert_eq!(env.archived("test")?.len(), 2);
+    env.enforce_retention()?;
+    assert_eq!(env.archived("test")?.last().unwrap().chunk, 2);
+
+    // Sweeps cut short after claiming a chunk file, or after archiving it, are picked up where they stopped.
+    let mut consumer = env.consumer("test", None)?;
+    let mut txn = env.write_txn()?;
+    assert_eq!(consumer.pop_front_n_in(&mut txn, 2)?.len(), 2);
+    txn.commit()?;
+    drop(consumer);
+    let paths: Vec<String> = (3..5).map(|file_num| env.chunk_path_at(&env.data_dirs[0], "test", file_num)).collect();
+    std::fs::rename(&paths[0], format!("{}.archiving", paths[0]))?;
+    let target = Path::new("/tmp/lmdb_queue_archive-archive/test").join(ArchivedChunk { chunk: 4, ..archived[0] }.file_name());
+    std::fs::rename(&paths[1], &target)?;
+    env.enforce_retention()?;
+    env.enforce_retention()?;
+    assert_eq!(env.archived("test")?.iter().map(|archived| archived.chunk).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    assert!(!Path::new(&format!("{}.archiving", paths[0])).exists() && target.exists());
+    let txn = env.read_txn()?;
+    let archive_db: Database<U64<BE>, Bytes> = env.open_db(&txn, "test_archive")?.unwrap();
+    assert!(archive_db.is_empty(&txn)?);
+    drop(txn);
+
+    // Replay into another topic.
+    assert_eq!(env.import_archived("test", &archived[1], "replay")?, 1);
+    assert_eq!(env.consumer("replay", None)?.pop_front()?.unwrap().data, b"message-000000000001");
+    assert!(env.import_archived("test", &ArchivedChunk { chunk: 7, ..archived[1] }, "replay").is_err());
+
+    // Chunks of a topic created again under the same name do not replace those archived before.
+    drop(producer);
+    env.delete_topic("test")?;
+    env.create_topic("test", &TopicConfig { chunk_size: 16, archive: true, ..Default::default() })?;
+    let mut producer = env.producer("test", None)?;
+    producer.push_back(b"second-0")?;
+    producer.push_back(b"second-1")?;
+    assert_eq!(env.consumer("test", None)?.pop_front_n(2)?.len(), 2);
+    let archived = env.archived("test")?;
+    assert_eq!(archived.iter().filter(|archived| archived.chunk == 0).count(), 2);
+    assert_ne!(archived[0].file_name(), archived[5].file_name());
+    let mut replay = env.consumer("replay", None)?;
+    assert_eq!(env.import_archived("test", &archived[5], "replay")?, 1);
+    assert_eq!(replay.pop_front()?.unwrap().data, b"second-0");
+    assert_eq!(env.import_archived("test", &archived[0], "replay")?, 1);
+    assert_eq!(replay.pop_front()?.unwrap().data, b"message-000000000000");
+
+    // Corrupted chunks are refused.
+    std::fs::write(Path::new("/tmp/lmdb_queue_archive-archive/test").join(archived[5].file_name()), [0; 20])?;
+    assert!(env.import_archived("test", &archived[5], "replay").is_err());
+
+    // A manifest line torn by a crash is skipped, the next chunk archived gets a line of its own.
+    let manifest = Path::new("/tmp/lmdb_queue_archive-archive/test/MANIFEST");
+    std::fs::OpenOptions::new().append(true).open(manifest)?.write_all(b"0000000000000007\t00")?;
+    assert_eq!(env.archived("test")?.len(), archived.len());
+    producer.push_back(b"second-2")?;
+    assert_eq!(env.consumer("test", None)?.pop_front()?.unwrap().data, b"second-2");
+    let listed = env.archived("test")?;
+    assert_eq!((listed.len(), listed.last().unwrap().chunk), (archived.len() + 1, 1));
+
+    // Compression is only available with the feature.
+    let config = TopicConfig { chunk_size: 16, archive: true, compress_archive: true, ..Default::default() };
+    if cfg!(feature = "compression") {
+        env.create_topic("compressed", &config)?;
+        let mut producer = env.producer("compressed", None)?;
+        producer.push_back(&[7; 4096])?;
+        producer.push_back(b"tail")?;
+        assert_eq!(env.consumer("compressed", None)?.pop_front_n(2)?.len(), 2);
+        let archived = env.archived("compressed")?;
+        assert!(archived[0].compressed && archived[0].file_name().ends_with(".gz"));
+        let path = Path::new("/tmp/lmdb_queue_archive-archive/compressed").join(archived[0].file_name());
+        assert!(std::fs::metadata(path)?.len() < 4096);
+        assert_eq!(env.import_archived("compressed", &archived[0], "replay")?, 1);
+        assert_eq!(replay.pop_front()?.unwrap().data, [7; 4096]);
+    } else {
+        assert!(env.create_topic("compressed", &config).is_err());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+/// Errors raised by the queue itself, as opposed to lmdb or io failures.
+///
+/// They are returned boxed like every other error, use `downcast_ref::<QueueError>()` to match on them.
+#[derive(Debug)]
+pub enum QueueError {
+    /// Every message of the push was rejected by the topic's deduplication window.
+    Duplicate,
+    /// The topic has not been created yet.
+    TopicNotFound(String),
+    /// `Env::create_topic` was called for a topic that already exists.
+    TopicExists(String),
+    /// Topic names are limited to ascii letters, digits, `_`, `-` and `.`, and must not collide
+    /// with the files of the environment, see `Env::create_topic`.
+    InvalidTopicName(String),
+    /// The push would take the topic past one of its quotas, see `TopicConfig::quota_policy`.
+    QuotaExceeded(String),
+    /// The `Env` was opened with `EnvOptions::read_only`, which rules out anything that writes.
+    ReadOnly,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Duplicate => write!(f, "duplicate message"),
+            QueueError::TopicNotFound(name) => write!(f, "topic {} not found", name),
+            QueueError::TopicExists(name) => write!(f, "topic {} already exists", name),
+            QueueError::InvalidTopicName(name) => write!(f, "invalid topic name {:?}", name),
+            QueueError::QuotaExceeded(name) => write!(f, "quota of topic {} exceeded", name),
+            QueueError::ReadOnly => write!(f, "environment is read only"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
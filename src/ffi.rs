@@ -68,6 +68,7 @@ pub struct CItem {
     pub ts: u64,
     pub data: *mut u8,
     pub len: usize,
+    pub offset: u64,
 }
 
 #[unsafe(no_mangle)]
@@ -80,7 +81,8 @@ pub unsafe extern "C" fn queue_consumer_pop(consumer: *mut Consumer) -> *mut CIt
             Box::into_raw(Box::new(CItem {
                 ts: item.ts,
                 data: Box::into_raw(data) as *mut u8,
-                len
+                len,
+                offset: item.offset,
             }))
         }
         Ok(None) => std::ptr::null_mut(),
@@ -99,7 +101,7 @@ pub unsafe extern "C" fn queue_item_free(ptr: *mut CItem) {
 
     let citem = unsafe { Box::from_raw(ptr) };
     if !citem.data.is_null() && citem.len > 0 {
-        let _ = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(citem.data, citem.len)) };
+        let _ = unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(citem.data, citem.len)) };
     }
 }
 
@@ -128,6 +130,7 @@ pub unsafe extern "C" fn queue_consumer_pop_n(
                     ts: item.ts,
                     data: data_ptr,
                     len,
+                    offset: item.offset,
                 });
             }
 
@@ -158,7 +161,7 @@ pub unsafe extern "C" fn queue_items_free(items: *mut CItem, count: libc::size_t
     let slice = unsafe { std::slice::from_raw_parts_mut(items, count) };
     for item in slice {
         if !item.data.is_null() && item.len > 0 {
-            let _ = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(item.data, item.len)) };
+            let _ = unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(item.data, item.len)) };
         }
     }
 
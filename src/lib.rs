@@ -1,10 +1,18 @@
 mod writer;
 mod reader;
 
+pub mod config;
 pub mod env;
+pub mod error;
+pub mod options;
+pub mod scanner;
 pub mod topic;
+pub mod transaction;
 
+pub use config::TopicConfig;
 pub use env::Env;
+pub use error::QueueError;
+pub use options::EnvOptions;
 
 #[cfg(feature = "ffi")]
-mod ffi;
\ No newline at end of file
+mod ffi;
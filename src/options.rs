@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use libc::{c_uint, size_t};
+
+use super::config::TopicConfig;
+use super::env::Env;
+
+/// How lmdb flushes its meta data to disk on commit.
+///
+/// Chunk files are never synced by the queue itself, so anything stronger than `NoSync` only
+/// protects the lmdb side against an os crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    /// Flush data and meta pages on every commit.
+    Sync,
+    /// Flush data pages but leave the meta page to the os, a crash may lose the last commit.
+    NoMetaSync,
+    /// Leave flushing to the os entirely.
+    #[default]
+    NoSync,
+}
+
+/// How chunk files are spread over the data directories of an `Env`.
+///
+/// The directory of every chunk is recorded in lmdb, so changing the policy or adding directories
+/// only affects chunks created afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placement {
+    /// All chunks of a topic go to one directory, picked from the topic name on creation.
+    #[default]
+    PerTopic,
+    /// Consecutive chunks of a topic cycle through the directories.
+    PerChunk,
+}
+
+/// Settings for opening an `Env`, `Env::new` covers the common case.
+///
+/// ```no_run
+/// use lmdb_queue::{EnvOptions, TopicConfig};
+/// use lmdb_queue::options::SyncMode;
+///
+/// let env = EnvOptions::new()
+///     .sync_mode(SyncMode::NoMetaSync)
+///     .data_dir("/data/chunks")
+///     .topic_config(TopicConfig { chunk_size: 16 * 1024 * 1024, ..Default::default() })
+///     .open("/data/queue")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct EnvOptions {
+    pub(crate) max_topics: c_uint,
+    pub(crate) map_size: size_t,
+    pub(crate) max_map_size: size_t,
+    pub(crate) max_readers: Option<u32>,
+    pub(crate) sync_mode: SyncMode,
+    pub(crate) sub_dir: bool,
+    pub(crate) read_only: bool,
+    pub(crate) data_dirs: Vec<PathBuf>,
+    pub(crate) placement: Placement,
+    pub(crate) archive_dir: Option<PathBuf>,
+    pub(crate) topic_config: TopicConfig,
+}
+
+impl Default for EnvOptions {
+    fn default() -> Self {
+        EnvOptions {
+            max_topics: 256,
+            map_size: 256 * 1024 * 1024,
+            max_map_size: usize::try_from(16u64 * 1024 * 1024 * 1024).unwrap_or(usize::MAX),
+            max_readers: None,
+            sync_mode: SyncMode::default(),
+            sub_dir: true,
+            read_only: false,
+            data_dirs: vec![],
+            placement: Placement::default(),
+            archive_dir: None,
+            topic_config: TopicConfig::default(),
+        }
+    }
+}
+
+impl EnvOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Topics the environment can hold, counting every name ever used: deleted topics keep their
+    /// lmdb databases, see `Env::delete_topic`.
+    pub fn max_topics(mut self, max_topics: c_uint) -> Self {
+        self.max_topics = max_topics;
+        self
+    }
+
+    /// Initial size of the lmdb map.
+    pub fn map_size(mut self, map_size: size_t) -> Self {
+        self.map_size = map_size;
+        self
+    }
+
+    /// Upper bound for growing the lmdb map when it is full, `map_size` disables growing.
+    pub fn max_map_size(mut self, max_map_size: size_t) -> Self {
+        self.max_map_size = max_map_size;
+        self
+    }
+
+    /// Concurrent read transactions across all processes, lmdb's default if unset.
+    pub fn max_readers(mut self, max_readers: u32) -> Self {
+        self.max_readers = Some(max_readers);
+        self
+    }
+
+    pub fn sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// Treat the root as a directory holding `data.mdb`, `lock.mdb` and a subdirectory of chunk
+    /// files per topic, which is the default. An environment found in the flat layout, where the
+    /// root is the lmdb file itself and chunk files are its siblings, is migrated on open.
+    ///
+    /// `false` keeps using the flat layout.
+    pub fn sub_dir(mut self, sub_dir: bool) -> Self {
+        self.sub_dir = sub_dir;
+        self
+    }
+
+    /// Open lmdb read only for inspection next to running processes, nothing is created, written
+    /// or removed. Producers, consumers and topic management fail with `QueueError::ReadOnly`,
+    /// `Env::topics`, `Env::topic_info` and `Env::peek` work as usual.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Directory for the topics' chunk file directories, the root if unset.
+    pub fn data_dir<P: AsRef<Path>>(self, data_dir: P) -> Self {
+        self.data_dirs([data_dir])
+    }
+
+    /// Several directories, e.g. on different disks, to spread chunk files over by `placement`.
+    ///
+    /// Only the directory layout supports more than one, see `sub_dir`.
+    pub fn data_dirs<I, P>(mut self, data_dirs: I) -> Self
+    where I: IntoIterator<Item = P>, P: AsRef<Path>
+    {
+        self.data_dirs = data_dirs.into_iter().map(|data_dir| data_dir.as_ref().to_path_buf()).collect();
+        self
+    }
+
+    pub fn placement(mut self, placement: Placement) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Directory archived chunks are moved to, `{root}-archive` if unset, see `TopicConfig::archive`.
+    ///
+    /// It may be on another file system, chunks are copied there when they cannot be renamed.
+    pub fn archive_dir<P: AsRef<Path>>(mut self, archive_dir: P) -> Self {
+        self.archive_dir = Some(archive_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Configuration of topics created implicitly by `Env::producer`.
+    pub fn topic_config(mut self, topic_config: TopicConfig) -> Self {
+        self.topic_config = topic_config;
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, root: P) -> Result<Env, Box<dyn Error>> {
+        Env::open(root.as_ref(), self)
+    }
+}
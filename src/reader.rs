@@ -6,66 +6,149 @@ use std::{
 };
 
 pub struct Reader {
-    fd: File,
-    prefix: String,
+    /// `None` until the chunk file has been created by a producer.
+    fd: Option<File>,
+    path: String,
     file_num: u64,
     bytes_read: u64,
 }
 
+/// The record has been punched out of its chunk file after being consumed, see `TopicConfig::punch_holes`.
+#[derive(Debug)]
+pub struct Reclaimed;
+
+impl std::fmt::Display for Reclaimed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Record has been reclaimed.")
+    }
+}
+
+impl std::error::Error for Reclaimed {}
+
 pub struct Item {
     pub ts: u64,
+    /// Global offset of the message within its topic, filled in by the consumer.
+    pub offset: u64,
     pub data: Vec<u8>,
 }
 
 impl Reader {
-    pub fn new(root: &str, topic_name: &str, file_num: u64) -> Result<Self> {
-        let prefix = format!("{}-{}", root, topic_name);
-        let path = format!("{}-{:016x}", prefix, file_num);
-        let fd = OpenOptions::new()
-            .read(true)
-            .open(path)?;
-
-        Ok(Self { fd, prefix, file_num, bytes_read: 0 })
+    /// Opens chunk `file_num` at `path`, see `Env::chunk_path_in`.
+    pub fn new(path: &str, file_num: u64) -> Result<Self> {
+        let mut reader = Self { fd: None, path: path.to_string(), file_num, bytes_read: 0 };
+        reader.open()?;
+        Ok(reader)
+    }
+
+    /// Opens the current chunk file if it exists by now, returns whether it is open.
+    fn open(&mut self) -> Result<bool> {
+        if self.fd.is_none() {
+            match OpenOptions::new().read(true).open(&self.path) {
+                Ok(mut fd) => {
+                    fd.seek(SeekFrom::Start(self.bytes_read))?;
+                    self.fd = Some(fd);
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether the chunk file exists, once opened it stays readable even if it is removed.
+    pub fn exists(&mut self) -> Result<bool> {
+        self.open()
     }
 
     pub fn get_file_num(&self) -> u64 {
         self.file_num
     }
 
-    pub fn rotate(&mut self, file_num: Option<u64>) -> Result<()> {
-        let old_path = format!("{}-{:016x}", self.prefix, self.file_num);
-        std::fs::remove_file(old_path).ok();
-
-        self.file_num = file_num.unwrap_or(self.file_num + 1);
+    /// Switches to chunk `file_num` at `path`, the old one is left for the consumer to remove after commit.
+    pub fn rotate(&mut self, file_num: u64, path: &str) -> Result<()> {
+        self.file_num = file_num;
+        self.path = path.to_string();
         self.bytes_read = 0;
-        let path = format!("{}-{:016x}", self.prefix, self.file_num);
-        self.fd = OpenOptions::new()
-            .read(true)
-            .open(path.clone())?;
+        self.fd = None;
+        self.open()?;
 
         Ok(())
     }
 
+    /// Reads the next record.
     pub fn read(&mut self) -> Result<Item> {
+        Ok(self.read_unexpired(None)?.expect("records only expire with a ttl"))
+    }
+
+    /// Reads the next record, or moves past it and returns `None` if it is older than `ttl` seconds.
+    ///
+    /// On failure the reader stays at the start of the record, so a retry reads it again.
+    pub fn read_unexpired(&mut self, ttl: Option<u64>) -> Result<Option<Item>> {
+        if !self.open()? {
+            return Err(anyhow!("Chunk file does not exist yet."));
+        }
+
+        let result = self.read_record(ttl);
+        if result.is_err() {
+            self.fd.as_mut().unwrap().seek(SeekFrom::Start(self.bytes_read))?;
+        }
+        result
+    }
+
+    fn read_record(&mut self, ttl: Option<u64>) -> Result<Option<Item>> {
+        let fd = self.fd.as_mut().unwrap();
         let mut head = vec![0; 4 + 8];
-        self.fd.read_exact(&mut head)?;
+        fd.read_exact(&mut head)?;
+
+        let data_len = u32::from_ne_bytes(head[0..4].try_into()?);
+        let ts = u64::from_ne_bytes(head[4..12].try_into()?);
+        // Writers never store a zero timestamp, this is a hole punched over consumed records.
+        if ts == 0 {
+            return Err(Reclaimed.into());
+        }
 
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("clock went backwards")
             .as_secs();
+        // A timestamp ahead of the clock comes from a producer whose clock runs ahead, not an old message.
+        if ttl.is_some_and(|ttl| ts.saturating_add(ttl) < now) {
+            fd.seek(SeekFrom::Current(data_len as i64))?;
+            self.bytes_read += data_len as u64 + 12;
+            return Ok(None);
+        }
 
-        let data_len = u32::from_ne_bytes(head[0..4].try_into()?);
-        let ts = u64::from_ne_bytes(head[4..12].try_into()?);
+        // The length is only trusted as far as the file goes, a record misread from the middle of
+        // another one must not allocate gigabytes.
+        let mut data = Vec::with_capacity((data_len as usize).min(64 * 1024));
+        if fd.by_ref().take(data_len as u64).read_to_end(&mut data)? < data_len as usize {
+            return Err(anyhow!("Record of {} bytes is cut short.", data_len));
+        }
+        self.bytes_read += data_len as u64 + 12;
+        Ok(Some(Item { ts, offset: 0, data }))
+    }
 
-        if ts > now || ts < now - 86400 * 10 {
-            return Err(anyhow!("Message expired."));
+    /// Moves past the next `n` records without reading their data.
+    ///
+    /// Fails with `Reclaimed` on reaching a hole, leaving the reader at the start of the record.
+    pub fn skip(&mut self, n: u64) -> Result<()> {
+        if !self.open()? {
+            return Err(anyhow!("Chunk file does not exist yet."));
         }
+        let fd = self.fd.as_mut().unwrap();
 
-        let mut data = vec![0; data_len as usize];
-        self.fd.read_exact(&mut data)?;
-        self.bytes_read += data_len as u64 + 12;
-        Ok(Item { ts: ts, data })
+        let mut head = vec![0; 4 + 8];
+        for _ in 0..n {
+            fd.read_exact(&mut head)?;
+            let data_len = u32::from_ne_bytes(head[0..4].try_into()?);
+            if u64::from_ne_bytes(head[4..12].try_into()?) == 0 {
+                fd.seek(SeekFrom::Start(self.bytes_read))?;
+                return Err(Reclaimed.into());
+            }
+            fd.seek(SeekFrom::Current(data_len as i64))?;
+            self.bytes_read += data_len as u64 + 12;
+        }
+        Ok(())
     }
 
     pub fn get_bytes_read(&self) -> u64 {
@@ -73,7 +156,9 @@ impl Reader {
     }
 
     pub fn set_bytes_read(&mut self, bytes_read: u64) -> Result<()> {
-        self.fd.seek(SeekFrom::Start(bytes_read))?;
+        if let Some(fd) = self.fd.as_mut() {
+            fd.seek(SeekFrom::Start(bytes_read))?;
+        }
         self.bytes_read = bytes_read;
         Ok(())
     }
@@ -81,21 +166,24 @@ impl Reader {
 
 #[test]
 fn test_reader() -> Result<()> {
-    let mut reader = Reader::new("/tmp/foo", "bar", 0)?;
+    let path = |file_num: u64| format!("/tmp/foo-bar-{:016x}", file_num);
+    let mut reader = Reader::new(&path(0), 0)?;
 
     let mut total = 0;
 
     loop {
         match reader.read() {
             Ok(item) => {
-                total = total + 1;
+                total += 1;
                 if total % (1024 * 1024) == 0 {
                     println!("Read {} messages, ts: {}, data: {}.", total, item.ts, String::from_utf8(item.data)?);
                 }
             }
             Err(_) => {
                 println!("Read {} messages.", total);
-                if let Err(_) = reader.rotate(None) {
+                std::fs::remove_file(path(reader.get_file_num())).ok();
+                let next = reader.get_file_num() + 1;
+                if reader.rotate(next, &path(next)).is_err() || !std::path::Path::new(&path(next)).exists() {
                     break;
                 }
             }
@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ops::{Bound, RangeBounds};
+
+use super::env::Env;
+use super::error::QueueError;
+use super::reader::Reader;
+use heed3::byteorder::BE;
+use heed3::types::*;
+use heed3::Database;
+
+use super::topic::{self, IndexEntry, Item, KEY_CONSUMER_BASE_OFFSET, KEY_CONSUMER_FILE};
+
+/// A retained chunk as of the scanner's creation.
+struct Chunk {
+    file_num: u64,
+    path: String,
+    /// Global offset of the chunk's first message.
+    first_offset: u64,
+    count: u64,
+    /// Global offset and byte position of the first message still on disk, which is past the
+    /// first one if a hole has been punched into the chunk.
+    start: (u64, u64),
+    /// The chunk's entries of the offset index, ordered by offset.
+    index: Vec<(u64, IndexEntry)>,
+}
+
+/// Iterates over the retained messages of a topic, consumed or not, without disturbing its consumers.
+///
+/// The chunks and their message counts are taken from a single read transaction when the scanner
+/// is created, later appends are not seen. Nothing is written, so scanners work on a read only
+/// `Env` too. Messages a consumer reclaims while the scan goes on are skipped, along with chunks
+/// it moves past.
+///
+/// ```no_run
+/// # let env = lmdb_queue::Env::new("/data/queue", None, None).unwrap();
+/// for item in env.scanner("events").unwrap().offsets(1000..2000) {
+///     let item = item.unwrap();
+///     println!("{} {} {:?}", item.offset, item.ts, item.data);
+/// }
+/// ```
+pub struct TopicScanner<'env> {
+    env: &'env Env,
+    name: String,
+    chunks: VecDeque<Chunk>,
+    reader: Option<Reader>,
+    /// File number and global offset of the first message of the current chunk.
+    chunk: (u64, u64),
+    /// Messages left to read in the current chunk.
+    remaining: u64,
+    next_offset: u64,
+    start_offset: u64,
+    end_offset: Option<u64>,
+    start_ts: u64,
+    end_ts: Option<u64>,
+    done: bool,
+}
+
+/// Converts range bounds into an inclusive start and an exclusive end.
+fn bounds<R: RangeBounds<u64>>(range: R) -> (u64, Option<u64>) {
+    let start = match range.start_bound() {
+        Bound::Included(start) => *start,
+        Bound::Excluded(start) => start.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(end) => Some(end.saturating_add(1)),
+        Bound::Excluded(end) => Some(*end),
+        Bound::Unbounded => None,
+    };
+    (start, end)
+}
+
+impl<'env> TopicScanner<'env> {
+    pub fn new(env: &'env Env, name: &str) -> Result<Self, Box<dyn Error>> {
+        let txn = env.read_txn()?;
+        let (producer_db, consumer_db) = match env.topic_dbs(&txn, name)? {
+            Some(dbs) => dbs,
+            None => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+
+        let head = consumer_db.get(&txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+        let (punched, punched_bytes) = topic::head_start(&txn, consumer_db)?;
+        let mut first_offset = consumer_db.get(&txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0);
+        let mut chunks = VecDeque::new();
+        for entry in producer_db.range(&txn, &(head..))? {
+            let (file_num, count) = entry?;
+            let start = if file_num == head { (first_offset + punched, punched_bytes) } else { (first_offset, 0) };
+            chunks.push_back(Chunk { file_num, path: env.chunk_path_in(&txn, name, file_num)?, first_offset, count, start, index: vec![] });
+            first_offset += count;
+        }
+
+        let index_db: Option<Database<U64<BE>, Bytes>> = env.open_db(&txn, &format!("{}_{}", name, "index"))?;
+        if let Some(index_db) = index_db {
+            for entry in index_db.iter(&txn)? {
+                let (offset, entry) = entry?;
+                let entry = IndexEntry::decode(entry)?;
+                if let Some(chunk) = chunks.iter_mut().find(|chunk| chunk.file_num == entry.chunk) {
+                    chunk.index.push((offset, entry));
+                }
+            }
+        }
+
+        drop(txn);
+        Ok(TopicScanner {
+            env,
+            name: name.to_string(),
+            next_offset: chunks.front().map_or(first_offset, |chunk| chunk.first_offset),
+            chunks,
+            reader: None,
+            chunk: (0, 0),
+            remaining: 0,
+            start_offset: 0,
+            end_offset: None,
+            start_ts: 0,
+            end_ts: None,
+            done: false,
+        })
+    }
+
+    /// Limits the scan to messages with global offsets in `range`.
+    pub fn offsets<R: RangeBounds<u64>>(mut self, range: R) -> Self {
+        (self.start_offset, self.end_offset) = bounds(range);
+        self
+    }
+
+    /// Limits the scan to messages appended within `range`, in seconds since the epoch.
+    ///
+    /// Timestamps are taken from the clock of the appending process and assumed not to go
+    /// backwards within a chunk, the scan seeks to the range in each chunk with the topic's offset
+    /// index and leaves the chunk at the first message past it.
+    pub fn timestamps<R: RangeBounds<u64>>(mut self, range: R) -> Self {
+        (self.start_ts, self.end_ts) = bounds(range);
+        self
+    }
+
+    /// Whether every message of `chunk` lies before the start offset of the scan.
+    ///
+    /// Chunks are never skipped by timestamp: with producers in several processes, or a clock
+    /// stepped back, a chunk may hold messages of the range after a later one started before it.
+    fn skipped(&self, chunk: &Chunk) -> bool {
+        chunk.first_offset + chunk.count <= self.start_offset
+    }
+
+    /// Opens the next chunk holding messages at or past the start of the scan, returns whether there is one.
+    ///
+    /// The reader starts at the last indexed record before the start rather than at the chunk's
+    /// beginning, records before the start offset are skipped by `read`.
+    fn next_chunk(&mut self) -> Result<bool, Box<dyn Error>> {
+        while let Some(chunk) = self.chunks.pop_front() {
+            if self.skipped(&chunk) {
+                continue;
+            }
+
+            let (offset, position) = chunk.index.iter()
+                .skip_while(|(offset, _)| *offset < chunk.start.0)
+                .take_while(|(offset, entry)| *offset <= self.start_offset || entry.ts < self.start_ts)
+                .last()
+                .map_or(chunk.start, |(offset, entry)| (*offset, entry.position));
+            let mut reader = Reader::new(&chunk.path, chunk.file_num)?;
+            reader.set_bytes_read(position)?;
+            self.reader = Some(reader);
+            self.next_offset = offset;
+            self.remaining = chunk.first_offset + chunk.count - offset;
+            self.chunk = (chunk.file_num, chunk.first_offset);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Whether the record at byte `position` of the current chunk, just read or skipped as the
+    /// message at `next_offset`, has been reclaimed since the scanner was created, see
+    /// `TopicConfig::punch_holes`.
+    ///
+    /// Holes are punched only after their commit and not always over whole records, so the
+    /// committed hole decides rather than what was read. Reclaimed messages are consumed and no
+    /// longer retained, the scan goes on from the end of the hole as committed now, or with the
+    /// next chunk once the consumer left this one.
+    fn reclaimed(&mut self, position: u64) -> Result<bool, Box<dyn Error>> {
+        let (head, punched, punched_bytes) = self.env.committed_head(&self.name)?;
+        let (file_num, first_offset) = self.chunk;
+        if head > file_num {
+            self.next_offset += self.remaining;
+            self.remaining = 0;
+            return Ok(true);
+        }
+        if head < file_num || position >= punched_bytes {
+            return Ok(false);
+        }
+
+        self.reader.as_mut().unwrap().set_bytes_read(punched_bytes)?;
+        self.remaining -= first_offset + punched - self.next_offset;
+        self.next_offset = first_offset + punched;
+        Ok(true)
+    }
+
+    fn read(&mut self) -> Result<Option<Item>, Box<dyn Error>> {
+        loop {
+            if self.remaining == 0 && !self.next_chunk()? {
+                return Ok(None);
+            }
+            if self.end_offset.is_some_and(|end| self.next_offset >= end) {
+                return Ok(None);
+            }
+
+            let reader = self.reader.as_mut().unwrap();
+            let position = reader.get_bytes_read();
+            if self.next_offset < self.start_offset {
+                let result = reader.skip(1);
+                if self.reclaimed(position)? {
+                    continue;
+                }
+                result?;
+                self.next_offset += 1;
+                self.remaining -= 1;
+                continue;
+            }
+
+            let result = reader.read();
+            if self.reclaimed(position)? {
+                continue;
+            }
+            let mut item = result?;
+            item.offset = self.next_offset;
+            self.next_offset += 1;
+            self.remaining -= 1;
+
+            // Like the start, the end only holds within the chunk, later ones may be back in range.
+            if self.end_ts.is_some_and(|end| item.ts >= end) {
+                self.next_offset += self.remaining;
+                self.remaining = 0;
+                continue;
+            }
+            if item.ts >= self.start_ts {
+                return Ok(Some(item));
+            }
+        }
+    }
+}
+
+impl Iterator for TopicScanner<'_> {
+    type Item = Result<Item, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
@@ -1,24 +1,726 @@
+use std::collections::HashSet;
 use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use heed3::byteorder::BE;
 use heed3::types::*;
-use heed3::{RwTxn, Database, PutFlags};
+use heed3::{RoTxn, RwTxn, Database};
+use sha2::{Digest, Sha256};
 
-use super::env::Env;
+use super::config::{DedupWindow, QuotaPolicy, TopicConfig, KEY_CONFIG_CHUNKS_TO_KEEP, KEY_CONFIG_CHUNK_SIZE};
+use super::env::{self, Env};
+use super::error::QueueError;
 
-use super::reader::{Reader, Item};
-use super::writer::Writer;
+use super::reader::Reader;
+use super::writer::{self, Writer};
+
+pub use super::reader::Item;
 
 pub static KEY_CONSUMER_FILE: &str = "FILE";
 pub static KEY_CONSUMER_OFFSET: &str = "OFFSET";
 pub static KEY_CONSUMER_BYTES_READ: &str = "BYTES_READ";
+pub static KEY_CONSUMER_BASE_OFFSET: &str = "BASE_OFFSET";
+pub static KEY_PRODUCER_BYTES_WRITTEN: &str = "BYTES_WRITTEN";
+/// Messages at the start of the head chunk that may have been punched out, see `TopicConfig::punch_holes`.
+pub static KEY_CONSUMER_PUNCHED: &str = "PUNCHED";
+pub static KEY_CONSUMER_PUNCHED_BYTES: &str = "PUNCHED_BYTES";
+/// Nanoseconds since the epoch the topic was created at, see `ArchivedChunk::generation`.
+pub static KEY_TOPIC_CREATED: &str = "CREATED";
+
+/// Where a message ended up after being appended to a topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Receipt {
+    /// Global offset of the message, counted from the first message ever written to the topic.
+    pub offset: u64,
+    /// Chunk file the message was written to.
+    pub chunk: u64,
+    /// Byte position of the record inside the chunk file.
+    pub position: u64,
+    /// Position of the message in the batch it was pushed with, which tells the written messages
+    /// apart when duplicates were skipped.
+    pub index: usize,
+}
+
+/// How often `Consumer::pop_front_wait` checks for new messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Longest topic name accepted, leaving room for the database suffixes.
+const MAX_TOPIC_NAME_LEN: usize = 200;
+
+/// Checks that `name` can be used for databases and as a directory name next to the environment's
+/// own files, see `Env::reserved_topic_names`.
+fn validate_topic_name(env: &Env, name: &str) -> Result<(), Box<dyn Error>> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_TOPIC_NAME_LEN
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && !env.reserved_topic_names().iter().any(|reserved| reserved == name);
+    if !valid {
+        return Err(QueueError::InvalidTopicName(name.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Creates the databases, chunk directory and initial state of a topic.
+///
+/// Returns `false` without touching anything if the topic already exists, names are only checked
+/// for new topics so those created before the checks keep working.
+pub(crate) fn create_topic(env: &Env, txn: &mut RwTxn, name: &str, config: &TopicConfig) -> Result<bool, Box<dyn Error>> {
+    config.validate()?;
+    let producer_db: Option<Database<U64<BE>, U64<BE>>> = env.open_db(txn, &format!("{}_{}", name, "producer"))?;
+    if let Some(producer_db) = producer_db
+        && !producer_db.is_empty(txn)?
+    {
+        return Ok(false);
+    }
+    validate_topic_name(env, name)?;
+    let producer_db: Database<U64<BE>, U64<BE>> = env.db(txn, &format!("{}_{}", name, "producer"))?;
+    let consumer_db: Database<Str, U64<BE>> = env.db(txn, &format!("{}_{}", name, "consumer"))?;
+    let config_db: Database<Str, U64<BE>> = env.db(txn, &format!("{}_{}", name, "config"))?;
+    env.place_chunk(txn, name, 0)?;
+    start_chunk(env, txn, name, 0)?;
+
+    producer_db.put(txn, &0, &0)?;
+    consumer_db.put(txn, KEY_CONSUMER_FILE, &0)?;
+    consumer_db.put(txn, KEY_CONSUMER_OFFSET, &0)?;
+    consumer_db.put(txn, KEY_CONSUMER_BYTES_READ, &0)?;
+    consumer_db.put(txn, KEY_CONSUMER_BASE_OFFSET, &0)?;
+    consumer_db.put(txn, KEY_PRODUCER_BYTES_WRITTEN, &0)?;
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock went backwards")
+        .as_nanos() as u64;
+    consumer_db.put(txn, KEY_TOPIC_CREATED, &created)?;
+    config.save(txn, config_db)?;
+    Ok(true)
+}
+
+/// Stores a setting passed to a handle for a topic that has none stored under `key` yet, one
+/// created before configurations were persisted, so its handles stop falling back to the defaults.
+fn seed_config(txn: &mut RwTxn, config_db: Database<Str, U64<BE>>, key: &str, value: Option<u64>) -> Result<(), Box<dyn Error>> {
+    if let Some(value) = value
+        && config_db.get(txn, key)?.is_none()
+    {
+        config_db.put(txn, key, &value)?;
+    }
+    Ok(())
+}
+
+/// Entry of a topic's sparse offset index, keyed by the global offset of the indexed message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub chunk: u64,
+    /// Byte position of the record inside the chunk file.
+    pub position: u64,
+    /// Timestamp of the record, for seeking by time.
+    pub ts: u64,
+}
+
+impl IndexEntry {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut entry = self.chunk.to_be_bytes().to_vec();
+        entry.extend_from_slice(&self.position.to_be_bytes());
+        entry.extend_from_slice(&self.ts.to_be_bytes());
+        entry
+    }
+
+    pub(crate) fn decode(entry: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(IndexEntry {
+            chunk: u64::from_be_bytes(entry[0..8].try_into()?),
+            position: u64::from_be_bytes(entry[8..16].try_into()?),
+            ts: u64::from_be_bytes(entry[16..24].try_into()?),
+        })
+    }
+}
+
+/// Adds the record at `offset` to the offset index if it starts a chunk or is one of the
+/// intervals of `config` past the last indexed record.
+///
+/// `last` is the newest entry of the index and is updated with the record if it is indexed.
+fn index_record(txn: &mut RwTxn, index_db: Database<U64<BE>, Bytes>, config: &TopicConfig, last: &mut Option<(u64, IndexEntry)>, offset: u64, entry: IndexEntry) -> Result<(), Box<dyn Error>> {
+    let due = match last {
+        Some((indexed, last_entry)) if last_entry.chunk == entry.chunk => {
+            config.index_messages.is_some_and(|n| offset.saturating_sub(*indexed) >= n)
+                || config.index_bytes.is_some_and(|n| entry.position.saturating_sub(last_entry.position) >= n)
+        },
+        _ => true,
+    };
+    if due {
+        index_db.put(txn, &offset, &entry.encode())?;
+        *last = Some((offset, entry));
+    }
+    Ok(())
+}
+
+/// Rebuilds the offset index of a topic from its retained chunk files, returns the number of entries.
+///
+/// Only committed records are read, so this is safe next to a producer appending to the tail.
+pub(crate) fn rebuild_index(env: &Env, txn: &mut RwTxn, name: &str, config: &TopicConfig) -> Result<u64, Box<dyn Error>> {
+    let producer_db: Database<U64<BE>, U64<BE>> = env.db(txn, &format!("{}_{}", name, "producer"))?;
+    let consumer_db: Database<Str, U64<BE>> = env.db(txn, &format!("{}_{}", name, "consumer"))?;
+    let index_db: Database<U64<BE>, Bytes> = env.db(txn, &format!("{}_{}", name, "index"))?;
+    index_db.clear(txn)?;
+
+    let mut offset = consumer_db.get(txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0);
+    let chunks = retained_chunks(env, txn, name, producer_db, consumer_db)?;
+
+    let mut last = None;
+    for (file_num, count, (punched, punched_bytes), path) in chunks {
+        let mut reader = Reader::new(&path, file_num)?;
+        reader.set_bytes_read(punched_bytes)?;
+        offset += punched;
+        for _ in punched..count {
+            let position = reader.get_bytes_read();
+            let item = reader.read()?;
+            index_record(txn, index_db, config, &mut last, offset, IndexEntry { chunk: file_num, position, ts: item.ts })?;
+            offset += 1;
+        }
+    }
+    Ok(index_db.len(txn)?)
+}
+
+/// Catalog entry of a chunk, kept in the topic's `{name}_chunks` database in step with the chunk
+/// file, so tools and retention need not open chunk files. See `Env::chunks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub chunk: u64,
+    /// Timestamp of the first message, 0 while the chunk is empty.
+    pub first_ts: u64,
+    /// Timestamp of the last message, 0 while the chunk is empty.
+    pub last_ts: u64,
+    /// Committed size of the chunk file.
+    pub bytes: u64,
+    /// Seconds since the epoch the chunk was started at.
+    pub created: u64,
+    /// Whether producers have moved on to a later chunk, so the file no longer grows.
+    pub sealed: bool,
+    /// CRC-32 of the committed bytes of the chunk file, see `Env::verify_chunk`.
+    pub checksum: u32,
+}
+
+impl ChunkInfo {
+    fn new(chunk: u64) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock went backwards")
+            .as_secs();
+        ChunkInfo { chunk, first_ts: 0, last_ts: 0, bytes: 0, created, sealed: false, checksum: 0 }
+    }
+
+    /// Seconds since the epoch of the newest message, or of the creation for an empty chunk.
+    pub fn last_modified(&self) -> u64 {
+        if self.last_ts > 0 { self.last_ts } else { self.created }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(45);
+        for value in [self.first_ts, self.last_ts, self.bytes, self.created] {
+            entry.extend_from_slice(&value.to_be_bytes());
+        }
+        entry.push(self.sealed as u8);
+        entry.extend_from_slice(&self.checksum.to_be_bytes());
+        entry
+    }
+
+    pub(crate) fn decode(chunk: u64, entry: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(ChunkInfo {
+            chunk,
+            first_ts: u64::from_be_bytes(entry[0..8].try_into()?),
+            last_ts: u64::from_be_bytes(entry[8..16].try_into()?),
+            bytes: u64::from_be_bytes(entry[16..24].try_into()?),
+            created: u64::from_be_bytes(entry[24..32].try_into()?),
+            sealed: entry[32] != 0,
+            checksum: u32::from_be_bytes(entry[33..37].try_into()?),
+        })
+    }
+}
+
+/// Catalog entry of a chunk, made up from the file for chunks the catalog does not know.
+pub(crate) fn chunk_info(env: &Env, txn: &RoTxn, name: &str, chunk: u64) -> Result<ChunkInfo, Box<dyn Error>> {
+    let catalog_db: Option<Database<U64<BE>, Bytes>> = env.open_db(txn, &format!("{}_{}", name, "chunks"))?;
+    if let Some(catalog_db) = catalog_db
+        && let Some(entry) = catalog_db.get(txn, &chunk)?
+    {
+        return ChunkInfo::decode(chunk, entry);
+    }
+
+    let metadata = std::fs::metadata(env.chunk_path_in(txn, name, chunk)?).ok();
+    let modified = metadata.as_ref()
+        .and_then(|m| m.modified().ok())
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_secs());
+    Ok(ChunkInfo { chunk, first_ts: 0, last_ts: 0, bytes: metadata.map_or(0, |m| m.len()), created: modified, sealed: false, checksum: 0 })
+}
+
+/// Adds a new, empty chunk to the catalog and seals the one before it.
+pub(crate) fn start_chunk(env: &Env, txn: &mut RwTxn, name: &str, chunk: u64) -> Result<(), Box<dyn Error>> {
+    let catalog_db: Database<U64<BE>, Bytes> = env.db(txn, &format!("{}_{}", name, "chunks"))?;
+    if let Some(previous) = chunk.checked_sub(1)
+        && let Some(entry) = catalog_db.get(txn, &previous)?
+    {
+        let info = ChunkInfo { sealed: true, ..ChunkInfo::decode(previous, entry)? };
+        catalog_db.put(txn, &previous, &info.encode())?;
+    }
+    catalog_db.put(txn, &chunk, &ChunkInfo::new(chunk).encode())?;
+    Ok(())
+}
+
+/// Starts chunk `chunk` as the new tail of a topic, returns the path of its file.
+///
+/// Producers move their writer over on their next append.
+pub(crate) fn start_tail(env: &Env, txn: &mut RwTxn, name: &str, producer_db: Database<U64<BE>, U64<BE>>, consumer_db: Database<Str, U64<BE>>, chunk: u64) -> Result<String, Box<dyn Error>> {
+    let path = env.place_chunk(txn, name, chunk)?;
+    start_chunk(env, txn, name, chunk)?;
+    producer_db.put(txn, &chunk, &0)?;
+    consumer_db.put(txn, KEY_PRODUCER_BYTES_WRITTEN, &0)?;
+    Ok(path)
+}
+
+/// Whether the tail chunk, holding `count` messages, is due for rotation by age or message count.
+pub(crate) fn rotation_due(config: &TopicConfig, info: &ChunkInfo, count: u64) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock went backwards")
+        .as_secs();
+    config.chunk_messages.is_some_and(|n| count >= n)
+        || config.chunk_age.is_some_and(|age| info.first_ts > 0 && now.saturating_sub(info.first_ts) >= age)
+}
+
+/// Rebuilds the chunk catalog of a topic from its retained chunk files.
+///
+/// Checksums are taken over the files as they are, and chunk creation times, which the files do
+/// not record, are replaced by their last modification. The first timestamp of a head chunk with
+/// a hole punched into it is that of its first message left.
+pub(crate) fn rebuild_catalog(env: &Env, txn: &mut RwTxn, name: &str) -> Result<(), Box<dyn Error>> {
+    let producer_db: Database<U64<BE>, U64<BE>> = env.db(txn, &format!("{}_{}", name, "producer"))?;
+    let consumer_db: Database<Str, U64<BE>> = env.db(txn, &format!("{}_{}", name, "consumer"))?;
+    let catalog_db: Database<U64<BE>, Bytes> = env.db(txn, &format!("{}_{}", name, "chunks"))?;
+    catalog_db.clear(txn)?;
+
+    let (tail, _) = tail_chunk(txn, producer_db, name)?;
+    let chunks = retained_chunks(env, txn, name, producer_db, consumer_db)?;
+
+    for (file_num, count, (punched, punched_bytes), path) in chunks {
+        let mut info = chunk_info(env, txn, name, file_num)?;
+        info.sealed = file_num < tail;
+        let mut reader = Reader::new(&path, file_num)?;
+        reader.set_bytes_read(punched_bytes)?;
+        for i in punched..count {
+            let item = reader.read()?;
+            if i == punched {
+                info.first_ts = item.ts;
+            }
+            info.last_ts = item.ts;
+        }
+        info.bytes = reader.get_bytes_read();
+        if info.bytes > 0 {
+            let mut data = std::fs::read(&path)?;
+            data.truncate(info.bytes as usize);
+            info.checksum = writer::crc32(0, &data);
+        }
+        catalog_db.put(txn, &file_num, &info.encode())?;
+    }
+    Ok(())
+}
+
+/// Chunk number, message count, `head_start` and path of a retained chunk.
+type RetainedChunk = (u64, u64, (u64, u64), String);
+
+fn retained_chunks(env: &Env, txn: &RoTxn, name: &str, producer_db: Database<U64<BE>, U64<BE>>, consumer_db: Database<Str, U64<BE>>) -> Result<Vec<RetainedChunk>, Box<dyn Error>> {
+    let head = consumer_db.get(txn, KEY_CONSUMER_FILE)?.unwrap_or(0);
+    let mut chunks = vec![];
+    for entry in producer_db.range(txn, &(head..))? {
+        let (file_num, count) = entry?;
+        let start = if file_num == head { head_start(txn, consumer_db)? } else { (0, 0) };
+        chunks.push((file_num, count, start, env.chunk_path_in(txn, name, file_num)?));
+    }
+    Ok(chunks)
+}
+
+/// Local offset and byte position of the first message of the head chunk that is still on disk.
+pub(crate) fn head_start(txn: &RoTxn, consumer_db: Database<Str, U64<BE>>) -> Result<(u64, u64), Box<dyn Error>> {
+    Ok((
+        consumer_db.get(txn, KEY_CONSUMER_PUNCHED)?.unwrap_or(0),
+        consumer_db.get(txn, KEY_CONSUMER_PUNCHED_BYTES)?.unwrap_or(0),
+    ))
+}
+
+/// Reads a counter of the consumer database, failing with `QueueError::TopicNotFound` if the topic
+/// has been deleted under an open handle, possibly by another process.
+pub(crate) fn consumer_key(txn: &RoTxn, consumer_db: Database<Str, U64<BE>>, name: &str, key: &str) -> Result<u64, Box<dyn Error>> {
+    consumer_db.get(txn, key)?.ok_or_else(|| QueueError::TopicNotFound(name.to_string()).into())
+}
+
+/// Chunk written to and its message count, see `consumer_key` for deleted topics.
+pub(crate) fn tail_chunk(txn: &RoTxn, producer_db: Database<U64<BE>, U64<BE>>, name: &str) -> Result<(u64, u64), Box<dyn Error>> {
+    producer_db.last(txn)?.ok_or_else(|| QueueError::TopicNotFound(name.to_string()).into())
+}
+
+/// Deallocates the first `len` bytes of a chunk file, rounded down to whole blocks, keeping its size.
+///
+/// Only the committed consumer position may be passed, no reader must ever come back to those bytes.
+#[cfg(target_os = "linux")]
+pub(crate) fn punch_hole(path: &str, len: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let len = len / 4096 * 4096;
+    if len == 0 {
+        return Ok(());
+    }
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, 0, len as libc::off_t) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Hole punching is linux only, elsewhere consumed data stays until its chunk is dropped.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn punch_hole(_path: &str, _len: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Moves the head of a topic past its oldest chunk.
+///
+/// Returns the dropped chunk and its path, or `None` if the head already is the chunk being written to.
+pub(crate) fn drop_head(env: &Env, txn: &mut RwTxn, name: &str, producer_db: Database<U64<BE>, U64<BE>>, consumer_db: Database<Str, U64<BE>>) -> Result<Option<(u64, String)>, Box<dyn Error>> {
+    let head = consumer_key(txn, consumer_db, name, KEY_CONSUMER_FILE)?;
+    let (tail, _) = tail_chunk(txn, producer_db, name)?;
+    if tail <= head {
+        return Ok(None);
+    }
+
+    let head_count = producer_db.get(txn, &head)?.unwrap_or(0);
+    let base_offset = consumer_db.get(txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0);
+    let path = env.chunk_path_in(txn, name, head)?;
+    let config = match env.open_db(txn, &format!("{}_{}", name, "config"))? {
+        Some(config_db) => TopicConfig::load(txn, config_db)?,
+        None => TopicConfig::default(),
+    };
+    if config.archive {
+        let info = chunk_info(env, txn, name, head)?;
+        let archive_db: Database<U64<BE>, Bytes> = env.db(txn, &format!("{}_{}", name, "archive"))?;
+        let archived = ArchivedChunk {
+            chunk: head,
+            generation: consumer_db.get(txn, KEY_TOPIC_CREATED)?.unwrap_or(0),
+            first_offset: base_offset,
+            messages: head_count,
+            first_ts: info.first_ts,
+            last_ts: info.last_ts,
+            bytes: info.bytes,
+            checksum: info.checksum,
+            compressed: config.compress_archive,
+            archived: 0,
+        };
+        archive_db.put(txn, &head, &archived.encode())?;
+    }
+    env.unplace_chunk(txn, name, head)?;
+    producer_db.delete(txn, &head)?;
+    consumer_db.put(txn, KEY_CONSUMER_FILE, &(head + 1))?;
+    consumer_db.put(txn, KEY_CONSUMER_OFFSET, &0)?;
+    consumer_db.put(txn, KEY_CONSUMER_BYTES_READ, &0)?;
+    consumer_db.put(txn, KEY_CONSUMER_BASE_OFFSET, &(base_offset + head_count))?;
+    consumer_db.put(txn, KEY_CONSUMER_PUNCHED, &0)?;
+    consumer_db.put(txn, KEY_CONSUMER_PUNCHED_BYTES, &0)?;
+    let index_db: Option<Database<U64<BE>, Bytes>> = env.open_db(txn, &format!("{}_{}", name, "index"))?;
+    if let Some(index_db) = index_db {
+        index_db.delete_range(txn, &(..base_offset + head_count))?;
+    }
+    let catalog_db: Option<Database<U64<BE>, Bytes>> = env.open_db(txn, &format!("{}_{}", name, "chunks"))?;
+    if let Some(catalog_db) = catalog_db {
+        catalog_db.delete(txn, &head)?;
+    }
+    record_garbage(env, txn, name, head, &path)?;
+    Ok(Some((head, path)))
+}
+
+/// Records a chunk file in the topic's garbage list, in the transaction that drops the chunk.
+///
+/// Files are only removed once that transaction has committed; should the process die in between,
+/// the list tells the next `Env::new` or `Env::enforce_retention` what is left to remove.
+pub(crate) fn record_garbage(env: &Env, txn: &mut RwTxn, name: &str, chunk: u64, path: &str) -> Result<(), Box<dyn Error>> {
+    let garbage_db: Database<U64<BE>, Str> = env.db(txn, &format!("{}_{}", name, "garbage"))?;
+    garbage_db.put(txn, &chunk, path)?;
+    Ok(())
+}
+
+/// Discards the files in the topic's garbage list and removes their entries, returns how many
+/// there were. Chunks that fail to be archived stay listed for the next sweep.
+///
+/// Entries visible to `txn` belong to committed drops as long as `txn` has not dropped anything itself.
+pub(crate) fn sweep_garbage(env: &Env, txn: &mut RwTxn, name: &str) -> Result<u64, Box<dyn Error>> {
+    let garbage_db: Option<Database<U64<BE>, Str>> = env.open_db(txn, &format!("{}_{}", name, "garbage"))?;
+    let Some(garbage_db) = garbage_db else {
+        return Ok(0);
+    };
+
+    let mut swept = vec![];
+    for entry in garbage_db.iter(txn)? {
+        let (chunk, path) = entry?;
+        if discard_chunk(env, txn, name, chunk, path).is_ok() {
+            swept.push(chunk);
+        }
+    }
+    forget_garbage(env, txn, name, &swept)?;
+    Ok(swept.len() as u64)
+}
+
+/// Removes discarded chunks from the topic's garbage list and their pending archive records.
+fn forget_garbage(env: &Env, txn: &mut RwTxn, name: &str, chunks: &[u64]) -> Result<(), Box<dyn Error>> {
+    let garbage_db: Option<Database<U64<BE>, Str>> = env.open_db(txn, &format!("{}_{}", name, "garbage"))?;
+    let archive_db: Option<Database<U64<BE>, Bytes>> = env.open_db(txn, &format!("{}_{}", name, "archive"))?;
+    for chunk in chunks {
+        if let Some(garbage_db) = garbage_db {
+            garbage_db.delete(txn, chunk)?;
+        }
+        if let Some(archive_db) = archive_db {
+            archive_db.delete(txn, chunk)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the file of a dropped chunk, or moves it to the archive if the topic archived the chunk
+/// on dropping it. `txn` must see the commit that dropped the chunk.
+pub(crate) fn discard_chunk(env: &Env, txn: &RoTxn, name: &str, chunk: u64, path: &str) -> Result<(), Box<dyn Error>> {
+    let archive_db: Option<Database<U64<BE>, Bytes>> = env.open_db(txn, &format!("{}_{}", name, "archive"))?;
+    let archived = match archive_db {
+        Some(archive_db) => archive_db.get(txn, &chunk)?.map(|entry| ArchivedChunk::decode(chunk, entry)).transpose()?,
+        None => None,
+    };
+    match archived {
+        Some(archived) => env.archive_chunk(name, archived, path),
+        None => {
+            std::fs::remove_file(path).ok();
+            Ok(())
+        },
+    }
+}
+
+/// Discards dropped chunks once the transaction that dropped them has committed, see `discard_chunk`,
+/// then takes them off the garbage list in a write transaction of its own.
+///
+/// Failures are left to the next sweep of the topic's garbage list.
+pub(crate) fn discard_chunks(env: &Env, name: &str, chunks: &[(u64, String)]) {
+    if chunks.is_empty() {
+        return;
+    }
+    let mut discarded = vec![];
+    if let Ok(txn) = env.read_txn() {
+        for (chunk, path) in chunks {
+            if discard_chunk(env, &txn, name, *chunk, path).is_ok() {
+                discarded.push(*chunk);
+            }
+        }
+    }
+    if discarded.is_empty() {
+        return;
+    }
+    if let Ok(mut txn) = env.write_txn()
+        && forget_garbage(env, &mut txn, name, &discarded).is_ok()
+    {
+        txn.commit().ok();
+    }
+}
+
+/// A chunk moved to the archive directory, as listed in its topic's manifest, see `TopicConfig::archive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchivedChunk {
+    pub chunk: u64,
+    /// Creation time of the topic in nanoseconds since the epoch, which tells apart the chunks of a
+    /// topic deleted and created again under the same name. 0 for topics older than the field.
+    pub generation: u64,
+    /// Global offset of the chunk's first message.
+    pub first_offset: u64,
+    pub messages: u64,
+    /// Timestamps of the first and last message, 0 for an empty chunk.
+    pub first_ts: u64,
+    pub last_ts: u64,
+    /// Size of the chunk file, before compression.
+    pub bytes: u64,
+    /// CRC-32 of the chunk file, before compression, checked by `Env::import_archived`.
+    pub checksum: u32,
+    /// Whether the archived file is gzip compressed, see `TopicConfig::compress_archive`.
+    pub compressed: bool,
+    /// Seconds since the epoch the chunk was listed in the manifest at.
+    pub archived: u64,
+}
+
+impl ArchivedChunk {
+    fn encode(&self) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(53);
+        for value in [self.first_offset, self.messages, self.first_ts, self.last_ts, self.bytes] {
+            entry.extend_from_slice(&value.to_be_bytes());
+        }
+        entry.extend_from_slice(&self.checksum.to_be_bytes());
+        entry.extend_from_slice(&self.generation.to_be_bytes());
+        entry.push(self.compressed as u8);
+        entry
+    }
+
+    fn decode(chunk: u64, entry: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(ArchivedChunk {
+            chunk,
+            generation: u64::from_be_bytes(entry[44..52].try_into()?),
+            first_offset: u64::from_be_bytes(entry[0..8].try_into()?),
+            messages: u64::from_be_bytes(entry[8..16].try_into()?),
+            first_ts: u64::from_be_bytes(entry[16..24].try_into()?),
+            last_ts: u64::from_be_bytes(entry[24..32].try_into()?),
+            bytes: u64::from_be_bytes(entry[32..40].try_into()?),
+            checksum: u32::from_be_bytes(entry[40..44].try_into()?),
+            compressed: entry[52] != 0,
+            archived: 0,
+        })
+    }
+
+    /// Name of the file in the topic's archive directory, unique across lifetimes of the topic.
+    pub fn file_name(&self) -> String {
+        let extension = if self.compressed { ".gz" } else { "" };
+        format!("{:016x}-{:016x}{}", self.generation, self.chunk, extension)
+    }
+
+    /// Line of the manifest, the fields in declaration order separated by tabs.
+    pub(crate) fn to_line(self) -> String {
+        format!("{:016x}\t{:016x}\t{}\t{}\t{}\t{}\t{}\t{:08x}\t{}\t{}\n",
+            self.chunk, self.generation, self.first_offset, self.messages, self.first_ts, self.last_ts, self.bytes,
+            self.checksum, self.compressed as u8, self.archived)
+    }
+
+    pub(crate) fn from_line(line: &str) -> Result<Self, Box<dyn Error>> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 10 {
+            return Err(format!("malformed manifest line {:?}", line).into());
+        }
+        Ok(ArchivedChunk {
+            chunk: u64::from_str_radix(fields[0], 16)?,
+            generation: u64::from_str_radix(fields[1], 16)?,
+            first_offset: fields[2].parse()?,
+            messages: fields[3].parse()?,
+            first_ts: fields[4].parse()?,
+            last_ts: fields[5].parse()?,
+            bytes: fields[6].parse()?,
+            checksum: u32::from_str_radix(fields[7], 16)?,
+            compressed: fields[8] != "0",
+            archived: fields[9].parse()?,
+        })
+    }
+}
+
+/// Drops the oldest chunks of a topic until it satisfies the retention settings of `config`.
+///
+/// Consumed or not, chunks go once there are more than `chunks_to_keep`, once the files exceed
+/// `max_bytes`, or once their newest message is older than `max_age`, as recorded in the chunk
+/// catalog. The tail chunk is always kept.
+/// Returns the dropped chunks and their files, which may only be removed after `txn` commits.
+pub(crate) fn enforce_retention(env: &Env, txn: &mut RwTxn, name: &str, producer_db: Database<U64<BE>, U64<BE>>, consumer_db: Database<Str, U64<BE>>, config: &TopicConfig) -> Result<Vec<(u64, String)>, Box<dyn Error>> {
+    let mut chunks = vec![];
+    let inspect = config.max_age.is_some() || config.max_bytes.is_some();
+    for entry in producer_db.iter(txn)? {
+        let (chunk, _) = entry?;
+        let (bytes, modified) = if inspect {
+            let info = chunk_info(env, txn, name, chunk)?;
+            (info.bytes, info.last_modified())
+        } else {
+            (0, 0)
+        };
+        chunks.push((chunk, bytes, modified));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock went backwards")
+        .as_secs();
+    let mut count = chunks.len() as u64;
+    let mut total: u64 = chunks.iter().map(|(_, bytes, _)| bytes).sum();
+    let mut dropped = vec![];
+    for (_, bytes, modified) in chunks {
+        let expired = config.max_age.is_some_and(|max_age| now.saturating_sub(modified) > max_age);
+        let oversized = config.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+        if !(count > config.chunks_to_keep || oversized || expired) {
+            break;
+        }
+        match drop_head(env, txn, name, producer_db, consumer_db)? {
+            Some(chunk) => dropped.push(chunk),
+            None => break,
+        }
+        count -= 1;
+        total -= bytes;
+    }
+    Ok(dropped)
+}
+
+/// Chunk files dropped by a handle, removed from disk once the dropping transaction has committed.
+///
+/// A write transaction id only grows once committed, so entries recorded under the current id
+/// may still be pending, while older ones are gone for good if the head has moved past them.
+/// The topic's persisted garbage list covers the files a crash keeps from being removed here.
+#[derive(Default)]
+struct Garbage(Vec<(usize, u64, String)>);
+
+impl Garbage {
+    fn push(&mut self, txn: &RoTxn, chunk: u64, path: String) {
+        self.0.push((txn.id(), chunk, path));
+    }
+
+    /// Drains all entries and returns the chunks below `head`, to be discarded after commit.
+    fn take(&mut self, head: u64) -> Vec<(u64, String)> {
+        std::mem::take(&mut self.0)
+            .into_iter()
+            .filter(|(_, chunk, _)| *chunk < head)
+            .map(|(_, chunk, path)| (chunk, path))
+            .collect()
+    }
+
+    /// Discards the files of chunks dropped by earlier transactions that have committed, and takes
+    /// them off the garbage list in `txn`.
+    fn collect(&mut self, env: &Env, txn: &mut RwTxn, name: &str, head: u64) -> Result<(), Box<dyn Error>> {
+        let txn_id = txn.id();
+        let mut discarded = vec![];
+        self.0.retain(|(id, chunk, path)| {
+            if *id == txn_id {
+                return true;
+            }
+            if *chunk < head && discard_chunk(env, txn, name, *chunk, path).is_ok() {
+                discarded.push(*chunk);
+            }
+            false
+        });
+        forget_garbage(env, txn, name, &discarded)
+    }
+}
+
+/// Snapshot of a topic's counters, see `Env::topic_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicInfo {
+    pub name: String,
+    /// Oldest chunk still on disk.
+    pub head_chunk: u64,
+    /// Chunk currently written to.
+    pub tail_chunk: u64,
+    pub chunks: u64,
+    /// Messages retained in the chunks, consumed or not.
+    pub messages: u64,
+    /// Size of the retained chunk files.
+    pub bytes: u64,
+    /// Global offset of the next message to be consumed.
+    pub head_offset: u64,
+    /// Global offset the next appended message will get.
+    pub tail_offset: u64,
+}
 
 pub trait Topic {
     fn get_env(&self) -> &Env;
     fn get_producer_db(&self) -> Database<U64<BE>, U64<BE>>;
     fn get_consumer_db(&self) -> Database<Str, U64<BE>>;
+    fn get_config_db(&self) -> Database<Str, U64<BE>>;
+
+    /// Current configuration of the topic, as stored by `Env::create_topic` or `Env::alter_topic`.
+    fn config(&self, txn: &RoTxn) -> Result<TopicConfig, Box<dyn Error>> {
+        TopicConfig::load(txn, self.get_config_db())
+    }
 
     fn lag(&self) -> Result<u64, Box<dyn Error>> {
-        let txn = self.get_env().write_txn()?;
+        let txn = self.get_env().read_txn()?;
 
         let mut pit = self.get_producer_db().iter(&txn)?.move_between_keys();
         let mut total: u64 = 0;
@@ -29,14 +731,93 @@ pub trait Topic {
         let head_offset = self.get_consumer_db().get(&txn, KEY_CONSUMER_OFFSET)?.unwrap_or(0);
         Ok(total - head_offset)
     }
+
+    /// Global offset of the first message in the head chunk.
+    fn base_offset(&self, txn: &RwTxn) -> Result<u64, Box<dyn Error>> {
+        Ok(self.get_consumer_db().get(txn, KEY_CONSUMER_BASE_OFFSET)?.unwrap_or(0))
+    }
 }
 
 pub struct Producer<'env> {
     env: &'env Env,
+    name: String,
     producer_db: Database<U64<BE>, U64<BE>>,
     consumer_db: Database<Str, U64<BE>>,
+    config_db: Database<Str, U64<BE>>,
+    /// Sparse offset index, global offset to `IndexEntry`.
+    index_db: Database<U64<BE>, Bytes>,
+    /// Chunk catalog, chunk number to `ChunkInfo`.
+    catalog_db: Database<U64<BE>, Bytes>,
     writer: Writer,
-    chunk_size: u64,
+    idempotence: Option<Idempotence>,
+    dedup: Dedup,
+    garbage: Garbage,
+}
+
+/// Persistent identity of an idempotent producer.
+///
+/// `sequence_db` maps each producer id to the next sequence number it is expected to commit,
+/// so a batch carrying a lower sequence has already been written and is dropped. Batches
+/// without an explicit sequence are tagged with that number, as seen by their own transaction,
+/// unless the last such call failed: its sequence is kept in `retry` until a call succeeds, as
+/// the failure may have come after the batch was committed.
+struct Idempotence {
+    producer_id: String,
+    sequence_db: Database<Str, U64<BE>>,
+    retry: Option<u64>,
+}
+
+/// Content-hash deduplication state of a topic.
+///
+/// `dedup_db` maps a message hash, see `dedup_hash`, to the offset it was written at,
+/// `dedup_log_db` holds the same entries ordered by offset (value: hash and timestamp) so the
+/// oldest ones can be evicted.
+struct Dedup {
+    dedup_db: Database<Bytes, U64<BE>>,
+    dedup_log_db: Database<U64<BE>, Bytes>,
+}
+
+impl Dedup {
+    fn evict(&self, txn: &mut RwTxn, window: DedupWindow, now: u64) -> Result<(), Box<dyn Error>> {
+        loop {
+            let (offset, hash, ts) = match self.dedup_log_db.first(txn)? {
+                Some((offset, entry)) => (offset, <[u8; 16]>::try_from(&entry[0..16])?, u64::from_be_bytes(entry[16..24].try_into()?)),
+                None => return Ok(()),
+            };
+
+            let expired = match window {
+                DedupWindow::Count(n) => self.dedup_log_db.len(txn)? > n,
+                DedupWindow::Seconds(secs) => ts + secs < now,
+            };
+            if !expired {
+                return Ok(());
+            }
+
+            self.dedup_log_db.delete(txn, &offset)?;
+            if self.dedup_db.get(txn, &hash)? == Some(offset) {
+                self.dedup_db.delete(txn, &hash)?;
+            }
+        }
+    }
+}
+
+/// First 128 bits of the SHA-256 of a message or key, identifying it in the dedup window.
+///
+/// Unlike `fnv1a`, collisions cannot be crafted from message contents, which would have a message
+/// silently dropped as the duplicate of a different one.
+fn dedup_hash(data: &[u8]) -> [u8; 16] {
+    let digest = Sha256::digest(data);
+    digest[..16].try_into().expect("SHA-256 digests are 32 bytes")
+}
+
+/// 64 bit FNV-1a, stable across builds so hashes can be persisted.
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 impl<'env> Topic for Producer<'env> {
@@ -51,60 +832,415 @@ impl<'env> Topic for Producer<'env> {
     fn get_consumer_db(&self) -> Database<Str, U64<BE>> {
         self.consumer_db
     }
+
+    fn get_config_db(&self) -> Database<Str, U64<BE>> {
+        self.config_db
+    }
 }
 
 impl<'env> Producer<'env> {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Opens a producer, creating the topic with the env's default configuration if it does not exist yet.
+    ///
+    /// `chunk_size` only applies when the topic is created here or has no stored configuration
+    /// yet, afterwards the stored configuration is authoritative, see `Env::alter_topic`.
     pub fn new(env: &'env Env, name: &str, chunk_size: Option<u64>) -> Result<Self, Box<dyn Error>> {
         let mut txn = env.write_txn()?;
+        let mut config = env.topic_config.clone();
+        config.chunk_size = chunk_size.unwrap_or(config.chunk_size);
+        create_topic(env, &mut txn, name, &config)?;
+
         let producer_db: Database<U64<BE>, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "producer"))?;
         let consumer_db: Database<Str, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "consumer"))?;
+        let config_db: Database<Str, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "config"))?;
+        seed_config(&mut txn, config_db, KEY_CONFIG_CHUNK_SIZE, chunk_size)?;
+        let dedup_db: Database<Bytes, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "dedup"))?;
+        let dedup_log_db: Database<U64<BE>, Bytes> = env.db(&mut txn, &format!("{}_{}", name, "dedup_log"))?;
+        let index_db: Database<U64<BE>, Bytes> = env.db(&mut txn, &format!("{}_{}", name, "index"))?;
+        let catalog_db: Database<U64<BE>, Bytes> = env.db(&mut txn, &format!("{}_{}", name, "chunks"))?;
 
-        if let Ok(_) = consumer_db.put_with_flags(&mut txn, PutFlags::NO_OVERWRITE, &KEY_CONSUMER_FILE, &0) {
-            producer_db.put_with_flags(&mut txn, PutFlags::NO_OVERWRITE, &0, &0)?;
-            consumer_db.put_with_flags(&mut txn, PutFlags::NO_OVERWRITE, &KEY_CONSUMER_OFFSET, &0)?;
-            consumer_db.put_with_flags(&mut txn, PutFlags::NO_OVERWRITE, &KEY_CONSUMER_BYTES_READ, &0)?;
+        let (tail_file, _) = tail_chunk(&txn, producer_db, name)?;
+        let writer = Writer::new(&env.chunk_path_in(&txn, name, tail_file)?, tail_file)?;
+
+        // Topics written before the index existed, or whose index was lost, get it back here.
+        if index_db.is_empty(&txn)? && producer_db.iter(&txn)?.any(|entry| entry.is_ok_and(|(_, count)| count > 0)) {
+            let config = TopicConfig::load(&txn, config_db)?;
+            rebuild_index(env, &mut txn, name, &config)?;
+        }
+        if catalog_db.is_empty(&txn)? {
+            rebuild_catalog(env, &mut txn, name)?;
         }
+        txn.commit()?;
+
+        let dedup = Dedup { dedup_db, dedup_log_db };
+        Ok(Producer { env, name: name.to_string(), producer_db, consumer_db, config_db, index_db, catalog_db, writer, idempotence: None, dedup, garbage: Garbage::default() })
+    }
 
-        let (tail_file, _) = producer_db.iter(&txn)?.last().transpose()?.unwrap();
-        let writer = Writer::new(&env.root, name, tail_file)?;
+    /// Opens a producer that deduplicates batches by `producer_id` and a monotonic sequence number.
+    ///
+    /// The next sequence is restored from lmdb, so a restarted producer continues after the last
+    /// committed batch. Whether a batch in flight when the process died was committed is not
+    /// known to it, see `push_back_batch` for retrying such batches.
+    pub fn new_idempotent(env: &'env Env, name: &str, producer_id: &str, chunk_size: Option<u64>) -> Result<Self, Box<dyn Error>> {
+        let mut producer = Self::new(env, name, chunk_size)?;
 
+        let mut txn = env.write_txn()?;
+        let sequence_db: Database<Str, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "sequences"))?;
         txn.commit()?;
 
-        Ok(Producer { env, producer_db, consumer_db, writer, chunk_size: chunk_size.unwrap_or(64 * 1024 * 1024) })
+        producer.idempotence = Some(Idempotence { producer_id: producer_id.to_string(), sequence_db, retry: None });
+        Ok(producer)
+    }
+
+    /// Sequence number the next batch will be written with, one past the last committed batch of
+    /// the producer id, or that of the last failed call. `None` if the producer is not idempotent.
+    pub fn sequence(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        let Some(idempotence) = self.idempotence.as_ref() else {
+            return Ok(None);
+        };
+        if idempotence.retry.is_some() {
+            return Ok(idempotence.retry);
+        }
+        let txn = self.env.read_txn()?;
+        Ok(Some(idempotence.sequence_db.get(&txn, &idempotence.producer_id)?.unwrap_or(0)))
+    }
+
+    /// Appends a batch of messages and returns one receipt per message, in order.
+    ///
+    /// With a dedup window configured on the topic, duplicates are skipped and only the written
+    /// messages get a receipt, see `Receipt::index`.
+    /// For an idempotent producer the batch is tagged with the current sequence number. A failed
+    /// call keeps it for the next one, so retrying the same batch after an error is dropped if the
+    /// failure came after its commit, and written otherwise; a different batch passed instead takes
+    /// the failed one's place. The retried sequence is only kept in memory: a batch retried after a
+    /// restart is tagged anew and may be written twice, callers that need to survive restarts
+    /// persist `sequence()` along with the batch and retry with `push_back_batch_with_sequence`.
+    pub fn push_back_batch<'a, B>(&mut self, messages: &'a B) -> Result<Vec<Receipt>, Box<dyn Error>>
+    where B: AsRef<[&'a [u8]]>
+    {
+        self.append(messages.as_ref(), None, None)
     }
 
-    pub fn push_back_batch<'a, B>(&mut self, messages: &'a B) -> Result<(), Box<dyn Error>>
+    /// Appends a batch tagged with an explicit sequence number.
+    ///
+    /// Batches whose sequence has already been committed by this producer id are silently dropped
+    /// and yield no receipts.
+    pub fn push_back_batch_with_sequence<'a, B>(&mut self, sequence: u64, messages: &'a B) -> Result<Vec<Receipt>, Box<dyn Error>>
     where B: AsRef<[&'a [u8]]>
     {
-        let mut txn = self.env.write_txn()?;
-        let (mut tail_file, mut offset) = self.producer_db.iter(&txn)?.last().transpose()?.unwrap();
-        if tail_file > self.writer.get_file_num() {
-            self.writer.rotate(Some(tail_file))?;
+        if self.idempotence.is_none() {
+            return Err("push_back_batch_with_sequence requires an idempotent producer".into());
         }
 
-        if self.writer.file_size()? > self.chunk_size {
-            self.writer.rotate(None)?;
+        self.append(messages.as_ref(), None, Some(sequence))
+    }
+
+    /// Appends in a transaction of its own; `sequence` tags the batch of an idempotent producer,
+    /// which otherwise gets that of the last failed call or the next one, see `push_back_batch`.
+    fn append(&mut self, messages: &[&[u8]], keys: Option<&[&[u8]]>, sequence: Option<u64>) -> Result<Vec<Receipt>, Box<dyn Error>> {
+        let start = Instant::now();
+        let mut sequence = sequence;
+        let automatic = sequence.is_none();
+        loop {
+            let mut txn = self.env.write_txn()?;
+            if let Some(idempotence) = self.idempotence.as_ref()
+                && sequence.is_none()
+            {
+                let committed = idempotence.sequence_db.get(&txn, &idempotence.producer_id)?.unwrap_or(0);
+                sequence = Some(idempotence.retry.unwrap_or(committed));
+            }
+            let result = self.append_in(&mut txn, messages, keys, sequence).and_then(|receipts| {
+                let dropped = self.take_dropped(&txn)?;
+                txn.commit()?;
+                discard_chunks(self.env, &self.name, &dropped);
+                Ok(receipts)
+            });
+
+            if let Err(e) = &result
+                && let Some(QueueError::QuotaExceeded(_)) = e.downcast_ref::<QueueError>()
+                && let QuotaPolicy::Block(timeout) = self.env.topic_config(&self.name)?.quota_policy
+                && start.elapsed() < timeout
+            {
+                std::thread::sleep(POLL_INTERVAL.min(timeout - start.elapsed()));
+                continue;
+            }
+
+            if let Err(e) = &result
+                && env::is_map_full(&**e)
+                && self.env.grow_map()?
+            {
+                self.recover()?;
+                continue;
+            }
+
+            if let Some(idempotence) = self.idempotence.as_mut().filter(|_| automatic) {
+                idempotence.retry = if result.is_err() { sequence } else { None };
+            }
+            if result.is_err() {
+                self.recover()?;
+            }
+            return result;
+        }
+    }
+
+    /// Returns the chunks retention dropped so far, to be discarded once `txn` commits.
+    pub(crate) fn take_dropped(&mut self, txn: &RwTxn) -> Result<Vec<(u64, String)>, Box<dyn Error>> {
+        let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
+        Ok(self.garbage.take(head))
+    }
+
+    /// Brings the chunk files back to the last committed state, dropping the bytes of aborted appends.
+    ///
+    /// This also happens at the start of every append, calling it only frees the space earlier.
+    pub fn recover(&mut self) -> Result<(), Box<dyn Error>> {
+        let txn = self.env.read_txn()?;
+        self.recover_in(&txn)
+    }
+
+    fn recover_in(&mut self, txn: &RoTxn) -> Result<(), Box<dyn Error>> {
+        let (tail_file, _) = tail_chunk(txn, self.producer_db, &self.name)?;
+        if tail_file != self.writer.get_file_num() {
+            self.writer.rotate(tail_file, &self.env.chunk_path_in(txn, &self.name, tail_file)?)?;
+            self.env.remove_chunks_after(&self.name, tail_file);
+        }
+
+        if let Some(bytes_written) = self.consumer_db.get(txn, KEY_PRODUCER_BYTES_WRITTEN)?
+            && self.writer.file_size()? > bytes_written
+        {
+            self.writer.truncate(bytes_written)?;
+        }
+        Ok(())
+    }
+
+    /// Appends a batch within a caller owned transaction, without committing it.
+    ///
+    /// Lets applications commit their own lmdb records atomically with the messages, e.g. for a
+    /// transactional outbox. If `txn` is aborted the appended bytes are dropped by the next append
+    /// or by `recover`. An idempotent producer tags the batch with its next sequence, which only
+    /// advances if `txn` commits.
+    pub fn push_back_batch_in<'a, B>(&mut self, txn: &mut RwTxn, messages: &'a B) -> Result<Vec<Receipt>, Box<dyn Error>>
+    where B: AsRef<[&'a [u8]]>
+    {
+        self.append_in(txn, messages.as_ref(), None, None)
+    }
+
+    /// Appends within `txn` without committing it.
+    ///
+    /// An idempotent producer tags the batch with `sequence`, or with the next sequence as seen by
+    /// `txn`, so batches appended earlier in the same transaction count while aborted ones do not.
+    pub(crate) fn append_in(&mut self, txn: &mut RwTxn, messages: &[&[u8]], keys: Option<&[&[u8]]>, sequence: Option<u64>) -> Result<Vec<Receipt>, Box<dyn Error>> {
+        let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
+        self.garbage.collect(self.env, txn, &self.name, head)?;
+
+        if let Some(idempotence) = self.idempotence.as_ref() {
+            let committed = idempotence.sequence_db.get(txn, &idempotence.producer_id)?.unwrap_or(0);
+            let sequence = sequence.unwrap_or(committed);
+            if sequence < committed {
+                return Ok(vec![]);
+            }
+            idempotence.sequence_db.put(txn, &idempotence.producer_id, &(sequence + 1))?;
+        }
+
+        let config = self.config(txn)?;
+        let mut hashes = vec![];
+        let accepted: Vec<usize> = match config.dedup_window {
+            Some(_) => {
+                let mut seen = HashSet::new();
+                let mut accepted = vec![];
+                for (i, message) in messages.iter().enumerate() {
+                    let hash = dedup_hash(keys.map_or(message, |keys| keys[i]));
+                    if seen.insert(hash) && self.dedup.dedup_db.get(txn, &hash)?.is_none() {
+                        accepted.push(i);
+                        hashes.push(hash);
+                    }
+                }
+                accepted
+            },
+            None => (0..messages.len()).collect(),
+        };
+        let messages: Vec<&[u8]> = accepted.iter().map(|i| messages[*i]).collect();
+        if messages.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.recover_in(txn)?;
+        self.check_quota(txn, &config, &messages)?;
+
+        let base_offset = self.base_offset(txn)?;
+        let mut total = 0;
+        for entry in self.producer_db.iter(txn)? {
+            total += entry?.1;
+        }
+
+        let (mut tail_file, mut offset) = tail_chunk(txn, self.producer_db, &self.name)?;
+        if self.writer.file_size()? > config.chunk_size || rotation_due(&config, &chunk_info(self.env, txn, &self.name, tail_file)?, offset) {
             tail_file += 1;
+            let path = start_tail(self.env, txn, &self.name, self.producer_db, self.consumer_db, tail_file)?;
+            self.writer.rotate(tail_file, &path)?;
+            self.writer.truncate(0)?;
             offset = 0;
-            self.producer_db.put(&mut txn, &tail_file, &0)?;
         }
-        self.writer.put_batch(messages)?;
-        self.producer_db.put(&mut txn, &tail_file, &(offset + messages.as_ref().len() as u64))?;
-        txn.commit()?;
-        Ok(())
+        let records = self.writer.put_batch(&messages)?;
+        self.producer_db.put(txn, &tail_file, &(offset + messages.len() as u64))?;
+        self.consumer_db.put(txn, KEY_PRODUCER_BYTES_WRITTEN, &self.writer.file_size()?)?;
+        let mut info = match self.catalog_db.get(txn, &tail_file)? {
+            Some(entry) => ChunkInfo::decode(tail_file, entry)?,
+            None => ChunkInfo::new(tail_file),
+        };
+        for (message, (_, ts)) in messages.iter().zip(&records) {
+            if info.first_ts == 0 {
+                info.first_ts = *ts;
+            }
+            info.last_ts = *ts;
+            info.checksum = writer::crc32_record(info.checksum, message, *ts);
+        }
+        info.bytes = self.writer.file_size()?;
+        self.catalog_db.put(txn, &tail_file, &info.encode())?;
+
+        let mut last_indexed = match self.index_db.last(txn)? {
+            Some((indexed, entry)) => Some((indexed, IndexEntry::decode(entry)?)),
+            None => None,
+        };
+        for (i, (position, ts)) in records.iter().enumerate() {
+            let entry = IndexEntry { chunk: tail_file, position: *position, ts: *ts };
+            index_record(txn, self.index_db, &config, &mut last_indexed, base_offset + total + i as u64, entry)?;
+        }
+
+        if let Some(window) = config.dedup_window {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("clock went backwards")
+                .as_secs();
+            for (i, hash) in hashes.iter().enumerate() {
+                let message_offset = base_offset + total + i as u64;
+                let mut entry = hash.to_vec();
+                entry.extend_from_slice(&now.to_be_bytes());
+                self.dedup.dedup_db.put(txn, hash, &message_offset)?;
+                self.dedup.dedup_log_db.put(txn, &message_offset, &entry)?;
+            }
+            self.dedup.evict(txn, window, now)?;
+        }
+
+        if let Some(capacity) = config.capacity {
+            self.enforce_capacity(txn, capacity)?;
+        }
+        for (chunk, path) in enforce_retention(self.env, txn, &self.name, self.producer_db, self.consumer_db, &config)? {
+            self.garbage.push(txn, chunk, path);
+        }
+
+        let receipts = records.into_iter()
+            .zip(accepted)
+            .enumerate()
+            .map(|(i, ((position, _), index))| Receipt { offset: base_offset + total + i as u64, chunk: tail_file, position, index })
+            .collect();
+        Ok(receipts)
     }
 
-    pub fn push_back<'a>(&mut self, message: &'a [u8]) -> Result<(), Box<dyn Error>> {
-        self.push_back_batch(&[message])
+    /// Skips the oldest unconsumed messages until at most `capacity` are left.
+    ///
+    /// Chunks skipped entirely are dropped, within the head chunk the consumer position is moved
+    /// forward, which consumers pick up on their next pop.
+    fn enforce_capacity(&mut self, txn: &mut RwTxn, capacity: u64) -> Result<(), Box<dyn Error>> {
+        loop {
+            let mut retained = 0;
+            for entry in self.producer_db.iter(txn)? {
+                retained += entry?.1;
+            }
+            let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
+            let offset = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_OFFSET)?;
+            let lag = retained - offset;
+            if lag <= capacity {
+                return Ok(());
+            }
+
+            let excess = lag - capacity;
+            let left = self.producer_db.get(txn, &head)?.unwrap_or(0) - offset;
+            if excess >= left
+                && let Some((chunk, path)) = drop_head(self.env, txn, &self.name, self.producer_db, self.consumer_db)?
+            {
+                self.garbage.push(txn, chunk, path);
+                continue;
+            }
+
+            let skip = excess.min(left);
+            let mut reader = Reader::new(&self.env.chunk_path_in(txn, &self.name, head)?, head)?;
+            reader.set_bytes_read(consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_BYTES_READ)?)?;
+            reader.skip(skip)?;
+            self.consumer_db.put(txn, KEY_CONSUMER_OFFSET, &(offset + skip))?;
+            self.consumer_db.put(txn, KEY_CONSUMER_BYTES_READ, &reader.get_bytes_read())?;
+            return Ok(());
+        }
+    }
+
+    /// Makes sure appending `messages` keeps the topic within its quotas.
+    ///
+    /// With `QuotaPolicy::DropOldest` the oldest chunks are dropped until they fit, otherwise or
+    /// if even that is not enough this fails with `QueueError::QuotaExceeded`.
+    fn check_quota(&mut self, txn: &mut RwTxn, config: &TopicConfig, messages: &[&[u8]]) -> Result<(), Box<dyn Error>> {
+        if config.quota_bytes.is_none() && config.quota_messages.is_none() && config.quota_lag.is_none() {
+            return Ok(());
+        }
+
+        let incoming_messages = messages.len() as u64;
+        let incoming_bytes: u64 = messages.iter().map(|message| 4 + 8 + message.len() as u64).sum();
+        loop {
+            let mut retained = 0;
+            let mut bytes = 0;
+            for entry in self.producer_db.iter(txn)? {
+                let (chunk, count) = entry?;
+                retained += count;
+                if config.quota_bytes.is_some() {
+                    bytes += chunk_info(self.env, txn, &self.name, chunk)?.bytes;
+                }
+            }
+            let lag = retained - consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_OFFSET)?;
+
+            let exceeded = config.quota_bytes.is_some_and(|quota| bytes + incoming_bytes > quota)
+                || config.quota_messages.is_some_and(|quota| retained + incoming_messages > quota)
+                || config.quota_lag.is_some_and(|quota| lag + incoming_messages > quota);
+            if !exceeded {
+                return Ok(());
+            }
+
+            let dropped = match config.quota_policy {
+                QuotaPolicy::DropOldest => drop_head(self.env, txn, &self.name, self.producer_db, self.consumer_db)?,
+                _ => None,
+            };
+            match dropped {
+                Some((chunk, path)) => self.garbage.push(txn, chunk, path),
+                None => return Err(QueueError::QuotaExceeded(self.name.clone()).into()),
+            }
+        }
+    }
+
+    /// Appends a single message, failing with `QueueError::Duplicate` if it was deduplicated.
+    pub fn push_back(&mut self, message: &[u8]) -> Result<Receipt, Box<dyn Error>> {
+        self.push_back_batch(&[message])?
+            .pop()
+            .ok_or_else(|| QueueError::Duplicate.into())
+    }
+
+    /// Like `push_back`, but deduplicates on `key` instead of the message content.
+    pub fn push_back_with_key(&mut self, key: &[u8], message: &[u8]) -> Result<Receipt, Box<dyn Error>> {
+        self.append(&[message], Some(&[key]), None)?
+            .pop()
+            .ok_or_else(|| QueueError::Duplicate.into())
     }
 }
 
 pub struct Consumer<'env> {
     env: &'env Env,
+    name: String,
     producer_db: Database<U64<BE>, U64<BE>>,
     consumer_db: Database<Str, U64<BE>>,
+    config_db: Database<Str, U64<BE>>,
     reader: Reader,
-    chunks_to_keep: u64,
+    garbage: Garbage,
+    /// Consumed prefix of a chunk file marked for hole punching by the current transaction.
+    hole: Option<(String, u64)>,
 }
 
 impl <'env> Topic for Consumer<'env> {
@@ -119,118 +1255,209 @@ impl <'env> Topic for Consumer<'env> {
     fn get_consumer_db(&self) -> Database<Str, U64<BE>> {
         self.consumer_db
     }
+
+    fn get_config_db(&self) -> Database<Str, U64<BE>> {
+        self.config_db
+    }
 }
 
 impl <'env> Consumer<'env> {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Opens a consumer on an existing topic, failing with `QueueError::TopicNotFound` otherwise.
+    ///
+    /// The topic may still be empty, `pop_front` simply returns `None` until data arrives.
+    /// Like `chunk_size` for producers, `chunks_to_keep` only applies to a topic without a stored
+    /// configuration yet, afterwards retention comes from the configuration, see `Env::alter_topic`.
     pub fn new(env: &'env Env, name: &str, chunks_to_keep: Option<u64>) -> Result<Self, Box<dyn Error>> {
         let mut txn = env.write_txn()?;
-        let producer_db: Database<U64<BE>, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "producer"))?;
-        let consumer_db: Database<Str, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "consumer"))?;
+        let producer_db: Option<Database<U64<BE>, U64<BE>>> = env.open_db(&txn, &format!("{}_{}", name, "producer"))?;
+        let consumer_db: Option<Database<Str, U64<BE>>> = env.open_db(&txn, &format!("{}_{}", name, "consumer"))?;
+        let (producer_db, consumer_db) = match (producer_db, consumer_db) {
+            (Some(producer_db), Some(consumer_db)) if !producer_db.is_empty(&txn)? => (producer_db, consumer_db),
+            _ => return Err(QueueError::TopicNotFound(name.to_string()).into()),
+        };
+        let config_db: Database<Str, U64<BE>> = env.db(&mut txn, &format!("{}_{}", name, "config"))?;
+        seed_config(&mut txn, config_db, KEY_CONFIG_CHUNKS_TO_KEEP, chunks_to_keep)?;
 
-        let file_num = consumer_db.get(&txn, &KEY_CONSUMER_FILE)?.unwrap();
-        let bytes_read = consumer_db.get(&txn, &KEY_CONSUMER_BYTES_READ)?.unwrap();
+        let file_num = consumer_key(&txn, consumer_db, name, KEY_CONSUMER_FILE)?;
+        let bytes_read = consumer_key(&txn, consumer_db, name, KEY_CONSUMER_BYTES_READ)?;
+        let path = env.chunk_path_in(&txn, name, file_num)?;
         txn.commit()?;
 
-        let mut reader = Reader::new(&env.root, name, file_num)?;
+        let mut reader = Reader::new(&path, file_num)?;
         if bytes_read > 0 {
             reader.set_bytes_read(bytes_read)?;
         }
 
-        Ok(Consumer { env, producer_db, consumer_db, reader, chunks_to_keep: chunks_to_keep.unwrap_or(8) })
+        Ok(Consumer { env, name: name.to_string(), producer_db, consumer_db, config_db, reader, garbage: Garbage::default(), hole: None })
+    }
+
+    /// Pops the next message, polling until one is available or `timeout` has passed.
+    pub fn pop_front_wait(&mut self, timeout: Duration) -> Result<Option<Item>, Box<dyn Error>> {
+        let start = Instant::now();
+        loop {
+            if let Some(item) = self.pop_front()? {
+                return Ok(Some(item));
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Ok(None);
+            }
+            std::thread::sleep(POLL_INTERVAL.min(timeout - elapsed));
+        }
     }
 
     pub fn pop_front_n(&mut self, n: u64) -> Result<Vec<Item>, Box<dyn Error>> {
-        let mut txn = self.env.write_txn()?;
-        self.check_chunks_to_keep(&mut txn)?;
+        let env = self.env;
+        env.retry_map_full(|| {
+            let mut txn = env.write_txn()?;
+            let items = self.pop_front_n_in(&mut txn, n)?;
+            let consumed = self.take_consumed(&txn)?;
+            let hole = self.take_hole();
+            txn.commit()?;
+
+            discard_chunks(self.env, &self.name, &consumed);
+            if let Some((path, len)) = hole {
+                punch_hole(&path, len).ok();
+            }
+            Ok(items)
+        })
+    }
+
+    pub fn pop_front(&mut self) -> Result<Option<Item>, Box<dyn Error>> {
+        Ok(self.pop_front_n(1)?.pop())
+    }
+
+    /// Pops up to `n` messages within a caller owned transaction, without committing it.
+    ///
+    /// Lets applications commit their own lmdb records atomically with the consumer offset.
+    /// Chunk files consumed on the way are removed by the next pop once the commit is visible,
+    /// holes are left to `Env::enforce_retention`; if `txn` is aborted the consumer resumes from
+    /// its last committed offset.
+    pub fn pop_front_n_in(&mut self, txn: &mut RwTxn, n: u64) -> Result<Vec<Item>, Box<dyn Error>> {
+        let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
+        self.garbage.collect(self.env, txn, &self.name, head)?;
+        self.hole = None;
+
+        let config = self.config(txn)?;
+        for (chunk, path) in enforce_retention(self.env, txn, &self.name, self.producer_db, self.consumer_db, &config)? {
+            self.garbage.push(txn, chunk, path);
+        }
+        self.sync_reader(txn)?;
 
         let mut items = vec![];
         let mut delta = 0;
-        for _ in 0..n {
-            match self.reader.read() {
-                Ok(item) => {
-                    items.push(item);
-                    delta += 1;
-                },
-                Err(_) => {
-                    if self.rotate(&mut txn)? {
-                        items.push(self.reader.read()?);
-                        delta = 1;
-                    } else {
-                        break;
-                    }
+        let ttl = config.ttl;
+        let mut next_offset = self.head_offset(txn)?;
+        let mut available = self.available(txn)?;
+        while (items.len() as u64) < n {
+            if available == 0 {
+                if !self.rotate(txn)? {
+                    break;
                 }
+                next_offset = self.head_offset(txn)?;
+                available = self.available(txn)?;
+                delta = 0;
+                continue;
             }
+
+            // Expired messages are skipped but count as consumed.
+            if let Some(mut item) = self.reader.read_unexpired(ttl)? {
+                item.offset = next_offset;
+                items.push(item);
+            }
+            next_offset += 1;
+            available -= 1;
+            delta += 1;
         }
 
-        self.inc_offset(&mut txn, delta)?;
-        txn.commit()?;
+        self.inc_offset(txn, delta)?;
+        if let Some(threshold) = config.punch_holes.filter(|_| !config.archive) {
+            self.mark_hole(txn, threshold)?;
+        }
         Ok(items)
     }
 
-    pub fn pop_front(&mut self) -> Result<Option<Item>, Box<dyn Error>> {
-        let mut txn = self.env.write_txn()?;
-        self.check_chunks_to_keep(&mut txn)?;
-
-        match self.reader.read() {
-            Ok(item) => {
-                self.inc_offset(&mut txn, 1)?;
-                txn.commit()?;
-                return Ok(Some(item));
-            },
-            Err(_) => {
-                if self.rotate(&mut txn)? {
-                    let item = self.reader.read()?;
-                    self.inc_offset(&mut txn, 1)?;
-                    txn.commit()?;
-                    return Ok(Some(item));
-                } else {
-                    txn.commit()?;
-                    return Ok(None);
-                }
-            }
+    /// Marks the consumed part of the head chunk as punched out once it has grown by `threshold`
+    /// bytes, the hole itself may only be punched after `txn` commits.
+    fn mark_hole(&mut self, txn: &mut RwTxn, threshold: u64) -> Result<(), Box<dyn Error>> {
+        let (_, punched_bytes) = head_start(txn, self.consumer_db)?;
+        let bytes_read = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_BYTES_READ)?;
+        if bytes_read < punched_bytes + threshold {
+            return Ok(());
         }
+
+        let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
+        let offset = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_OFFSET)?;
+        self.consumer_db.put(txn, KEY_CONSUMER_PUNCHED, &offset)?;
+        self.consumer_db.put(txn, KEY_CONSUMER_PUNCHED_BYTES, &bytes_read)?;
+        self.hole = Some((self.env.chunk_path_in(txn, &self.name, head)?, bytes_read));
+        Ok(())
+    }
+
+    /// Returns the hole marked by the last pop, to be punched once its transaction has committed.
+    pub(crate) fn take_hole(&mut self) -> Option<(String, u64)> {
+        self.hole.take()
+    }
+
+    pub fn pop_front_in(&mut self, txn: &mut RwTxn) -> Result<Option<Item>, Box<dyn Error>> {
+        Ok(self.pop_front_n_in(txn, 1)?.pop())
+    }
+
+    /// Returns the chunks the head has moved past in `txn`, to be discarded once it commits.
+    pub(crate) fn take_consumed(&mut self, txn: &RwTxn) -> Result<Vec<(u64, String)>, Box<dyn Error>> {
+        let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
+        Ok(self.garbage.take(head))
+    }
+
+    /// Global offset of the next message to be consumed.
+    fn head_offset(&self, txn: &RwTxn) -> Result<u64, Box<dyn Error>> {
+        let offset = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_OFFSET)?;
+        Ok(self.base_offset(txn)? + offset)
+    }
+
+    /// Committed messages left in the head chunk; bytes past them may belong to an aborted append.
+    fn available(&self, txn: &RwTxn) -> Result<u64, Box<dyn Error>> {
+        let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
+        let count = self.producer_db.get(txn, &head)?.unwrap_or(0);
+        let offset = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_OFFSET)?;
+        Ok(count.saturating_sub(offset))
     }
 
     fn inc_offset(&mut self, txn: &mut RwTxn, delta: u64) -> Result<(), Box<dyn Error>> {
-        let offset = self.consumer_db.get(&txn, &KEY_CONSUMER_OFFSET)?.unwrap();
-        self.consumer_db.put(txn, &KEY_CONSUMER_OFFSET, &(offset + delta))?;
+        let offset = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_OFFSET)?;
+        self.consumer_db.put(txn, KEY_CONSUMER_OFFSET, &(offset + delta))?;
 
-        self.consumer_db.put(txn, &KEY_CONSUMER_BYTES_READ, &self.reader.get_bytes_read())?;
+        self.consumer_db.put(txn, KEY_CONSUMER_BYTES_READ, &self.reader.get_bytes_read())?;
         Ok(())
     }
 
-    fn check_chunks_to_keep(&mut self, txn: &mut RwTxn) -> Result<(), Box<dyn Error>> {
-        let head = self.consumer_db.get(&txn, &KEY_CONSUMER_FILE)?.unwrap();
+    /// Moves the reader to the committed head, which a producer or another consumer may have moved.
+    fn sync_reader(&mut self, txn: &RwTxn) -> Result<(), Box<dyn Error>> {
+        let head = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_FILE)?;
         if head != self.reader.get_file_num() {
-            self.reader.rotate(Some(head))?;
+            self.reader.rotate(head, &self.env.chunk_path_in(txn, &self.name, head)?)?;
         }
 
-        let bytes_read = self.consumer_db.get(&txn, &KEY_CONSUMER_BYTES_READ)?.unwrap();
+        let bytes_read = consumer_key(txn, self.consumer_db, &self.name, KEY_CONSUMER_BYTES_READ)?;
         if bytes_read != self.reader.get_bytes_read() {
             self.reader.set_bytes_read(bytes_read)?;
         }
-
-        let (tail, _) = self.producer_db.iter(&txn)?.last().transpose()?.unwrap();
-        let chunk_to_remove: i64 = tail as i64 + 1 - head as i64 - self.chunks_to_keep as i64;
-        for _ in 0..chunk_to_remove {
-            self.rotate(txn)?;
-        }
-
         Ok(())
     }
 
     fn rotate(&mut self, txn: &mut RwTxn) -> Result<bool, Box<dyn Error>> {
-        let head = self.consumer_db.get(&txn, &KEY_CONSUMER_FILE)?.unwrap();
-        let (tail, _) = self.producer_db.iter(&txn)?.last().transpose()?.unwrap();
-        if tail > head {
-            self.reader.rotate(None)?;
-            self.producer_db.delete(txn, &head)?;
-            self.consumer_db.put(txn, &KEY_CONSUMER_FILE, &(head + 1))?;
-            self.consumer_db.put(txn, &KEY_CONSUMER_OFFSET, &0)?;
-            self.consumer_db.put(txn, &KEY_CONSUMER_BYTES_READ, &0)?;
-            Ok(true)
-        } else {
-            Ok(false)
+        match drop_head(self.env, txn, &self.name, self.producer_db, self.consumer_db)? {
+            Some((head, path)) => {
+                self.garbage.push(txn, head, path);
+                self.reader.rotate(head + 1, &self.env.chunk_path_in(txn, &self.name, head + 1)?)?;
+                Ok(true)
+            },
+            None => Ok(false),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,66 @@
+use std::error::Error;
+use heed3::RwTxn;
+
+use super::env::{Env, Txn};
+use super::error::QueueError;
+use super::topic::{self, Consumer, Item, Producer, Receipt};
+
+/// A single lmdb write transaction shared by pops and pushes on several topics.
+///
+/// Consumer offsets and producer appends made through it become visible together on `commit`.
+/// Dropping it without committing aborts everything: the bytes already appended to chunk files
+/// are truncated by the producers' next append, and consumers resume from their committed offset.
+/// All handles must belong to the `Env` the transaction was started from.
+pub struct Transaction<'env> {
+    env: &'env Env,
+    txn: Txn<'env, RwTxn<'env>>,
+    /// Chunks consumed or dropped by topic, to discard after commit.
+    consumed: Vec<(String, Vec<(u64, String)>)>,
+    /// Consumed prefixes of chunk files to punch out after commit.
+    holes: Vec<(String, u64)>,
+}
+
+impl<'env> Transaction<'env> {
+    pub fn new(env: &'env Env) -> Result<Self, Box<dyn Error>> {
+        Ok(Transaction { env, txn: env.write_txn()?, consumed: vec![], holes: vec![] })
+    }
+
+    pub fn pop_front_n(&mut self, consumer: &mut Consumer, n: u64) -> Result<Vec<Item>, Box<dyn Error>> {
+        let items = consumer.pop_front_n_in(&mut self.txn, n)?;
+        self.consumed.push((consumer.name().to_string(), consumer.take_consumed(&self.txn)?));
+        self.holes.extend(consumer.take_hole());
+        Ok(items)
+    }
+
+    pub fn pop_front(&mut self, consumer: &mut Consumer) -> Result<Option<Item>, Box<dyn Error>> {
+        Ok(self.pop_front_n(consumer, 1)?.pop())
+    }
+
+    /// Appends a batch, see `Producer::push_back_batch`.
+    pub fn push_back_batch<'a, B>(&mut self, producer: &mut Producer, messages: &'a B) -> Result<Vec<Receipt>, Box<dyn Error>>
+    where B: AsRef<[&'a [u8]]>
+    {
+        let receipts = producer.push_back_batch_in(&mut self.txn, messages)?;
+        self.consumed.push((producer.name().to_string(), producer.take_dropped(&self.txn)?));
+        Ok(receipts)
+    }
+
+    pub fn push_back(&mut self, producer: &mut Producer, message: &[u8]) -> Result<Receipt, Box<dyn Error>> {
+        self.push_back_batch(producer, &[message])?
+            .pop()
+            .ok_or_else(|| QueueError::Duplicate.into())
+    }
+
+    /// Commits every pop and push, then removes or archives the chunk files consumed or dropped along the way
+    /// and punches the holes consumers marked.
+    pub fn commit(self) -> Result<(), Box<dyn Error>> {
+        self.txn.commit()?;
+        for (name, chunks) in &self.consumed {
+            topic::discard_chunks(self.env, name, chunks);
+        }
+        for (path, len) in self.holes {
+            topic::punch_hole(&path, len).ok();
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,64 @@
 use anyhow::Result;
 use std::{fs::{File, OpenOptions}, io::Write, time::{SystemTime, UNIX_EPOCH}};
 
+/// Table of the reflected IEEE polynomial for `crc32`.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues the IEEE CRC-32 `crc` over `data`, starting from 0.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Continues `crc` over the record `put_batch` writes for `message` with timestamp `ts`.
+pub fn crc32_record(crc: u32, message: &[u8], ts: u64) -> u32 {
+    let crc = crc32(crc, &(message.len() as u32).to_ne_bytes());
+    let crc = crc32(crc, &ts.to_ne_bytes());
+    crc32(crc, message)
+}
+
 pub struct Writer {
     fd: File,
-    prefix: String,
     file_num: u64,
 }
 
 impl Writer {
-    pub fn new(root: &str, topic_name: &str, file_num: u64) -> Result<Self> {
-        let prefix = format!("{}-{}", root, topic_name);
-        let path = format!("{}-{:016x}", prefix, file_num);
-
+    /// Opens chunk `file_num` at `path` for appending, creating it if needed.
+    pub fn new(path: &str, file_num: u64) -> Result<Self> {
         let mut fd = OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
             .open(path)?;
 
         fd.write_all(b"")?;
         fd.sync_all()?;
-        Ok(Self { fd, prefix, file_num })
+        Ok(Self { fd, file_num })
     }
 
     pub fn get_file_num(&self) -> u64 {
         self.file_num
     }
 
-    pub fn rotate(&mut self, file_num: Option<u64>) -> Result<()> {
-        self.file_num = file_num.unwrap_or(self.file_num + 1);
-        let path = format!("{}-{:016x}", self.prefix, self.file_num);
+    pub fn rotate(&mut self, file_num: u64, path: &str) -> Result<()> {
+        self.file_num = file_num;
         self.fd = OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
             .open(path)?;
@@ -39,7 +66,14 @@ impl Writer {
         Ok(())
     }
 
-    fn append(&mut self, message: &[u8]) -> Result<()> {
+    /// Cuts the current chunk file down to `size` bytes.
+    pub fn truncate(&mut self, size: u64) -> Result<()> {
+        self.fd.set_len(size)?;
+        Ok(())
+    }
+
+    /// Writes one record and returns its timestamp.
+    fn append(&mut self, message: &[u8]) -> Result<u64> {
         let mut buf = Vec::with_capacity(4 + 8 + message.len());
         let len = message.len() as u32;
         let ts = SystemTime::now()
@@ -50,17 +84,21 @@ impl Writer {
         buf.extend_from_slice(&ts.to_ne_bytes());
         buf.extend_from_slice(message);
 
-        self.fd.write(&buf)?;
-        Ok(())
+        self.fd.write_all(&buf)?;
+        Ok(ts)
     }
 
-    pub fn put_batch<'a, B>(&mut self, messages: &'a B) -> Result<()>
+    /// Appends every message and returns the byte position and timestamp of each record.
+    pub fn put_batch<'a, B>(&mut self, messages: &'a B) -> Result<Vec<(u64, u64)>>
     where B: AsRef<[&'a [u8]]>
     {
+        let mut position = self.file_size()?;
+        let mut records = Vec::with_capacity(messages.as_ref().len());
         for message in messages.as_ref() {
-            self.append(message)?;
+            records.push((position, self.append(message)?));
+            position += 4 + 8 + message.len() as u64;
         }
-        Ok(())
+        Ok(records)
     }
 
     pub fn file_size(&self) -> Result<u64> {
@@ -70,7 +108,8 @@ impl Writer {
 
 #[test]
 fn test_put_batch() -> Result<()> {
-    let mut writer = Writer::new("/tmp/foo", "bar", 0)?;
+    let path = |file_num: u64| format!("/tmp/foo-bar-{:016x}", file_num);
+    let mut writer = Writer::new(&path(0), 0)?;
 
     for i in 0..1024*256 {
         let messages: Vec<Vec<u8>> = (0..10)
@@ -79,10 +118,46 @@ fn test_put_batch() -> Result<()> {
 
         let batch: Vec<&[u8]> = messages.iter().map(|v| v.as_slice()).collect();
         if i == 1024 * 128 {
-            writer.rotate(None)?;
+            writer.rotate(1, &path(1))?;
         }
         writer.put_batch(&batch)?;
     }
 
     Ok(())
 }
+
+#[test]
+fn test_truncate() -> Result<()> {
+    let path = |file_num: u64| format!("/tmp/lmdb_queue_truncate-bar-{:016x}", file_num);
+    std::fs::remove_file(path(0)).ok();
+    let mut writer = Writer::new(&path(0), 0)?;
+    writer.put_batch(&[b"committed".as_slice()])?;
+    let size = writer.file_size()?;
+
+    writer.put_batch(&[b"aborted".as_slice()])?;
+    writer.rotate(1, &path(1))?;
+    writer.put_batch(&[b"aborted".as_slice()])?;
+    writer.rotate(0, &path(0))?;
+    writer.truncate(size)?;
+
+    assert_eq!(writer.put_batch(&[b"next".as_slice()])?[0].0, 12 + 9);
+    std::fs::remove_file(path(1))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_crc32() -> Result<()> {
+    assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+    assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+
+    let path = "/tmp/lmdb_queue_crc32";
+    std::fs::remove_file(path).ok();
+    let mut writer = Writer::new(path, 0)?;
+    let records = writer.put_batch(&[b"hello".as_slice(), b"world".as_slice()])?;
+    let crc = crc32_record(crc32_record(0, b"hello", records[0].1), b"world", records[1].1);
+    assert_eq!(crc, crc32(0, &std::fs::read(path)?));
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}